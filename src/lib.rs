@@ -1,19 +1,61 @@
-use spacetimedb::{reducer, table, Identity, ReducerContext, Table, Timestamp};
+use std::collections::HashSet;
+use std::time::Duration;
 
+use spacetimedb::spacetimedb_lib::{hash_bytes, Hash};
+use spacetimedb::{
+    reducer, table, Identity, ReducerContext, ScheduleAt, SpacetimeType, Table, Timestamp,
+};
+
+use serde::{Deserialize, Serialize};
 use spacetimedb::{client_visibility_filter, Filter};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
 /// A client can only see their account
 #[client_visibility_filter]
 const ACCOUNT_FILTER: Filter = Filter::Sql("SELECT * FROM user WHERE identity = :sender");
 
-/// Only authorized clients can see messages
+/// Only authorized clients can see messages sent to the room as a whole
 #[client_visibility_filter]
 const MESSAGE_FILTER: Filter = Filter::Sql(
     r#"
     SELECT m.*
     FROM message m
     JOIN user u ON u.dummy_join = m.dummy_join
-    WHERE u.authorized = true AND u.identity = :sender
+    WHERE u.authorized = true AND u.identity = :sender AND m.channel_id = 0 AND m.hidden = false
+"#,
+);
+
+/// When guest read access is enabled, anyone can see messages sent to the room as a whole
+#[client_visibility_filter]
+const GUEST_MESSAGE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT m.*
+    FROM message m
+    JOIN config c ON c.dummy_join = m.dummy_join
+    WHERE c.guest_read_access = true AND m.channel_id = 0 AND m.hidden = false
+"#,
+);
+
+/// Channel messages are only visible to the channel's members
+#[client_visibility_filter]
+const CHANNEL_MESSAGE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT m.*
+    FROM message m
+    JOIN channel_member cm ON cm.channel_id = m.channel_id
+    WHERE cm.identity = :sender AND m.hidden = false
+"#,
+);
+
+/// Admins can still see messages hidden by reports
+#[client_visibility_filter]
+const HIDDEN_MESSAGE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT m.*
+    FROM message m
+    JOIN user u ON u.dummy_join = m.dummy_join
+    WHERE u.admin = true AND u.identity = :sender AND m.hidden = true
 "#,
 );
 
@@ -21,137 +63,9081 @@ const MESSAGE_FILTER: Filter = Filter::Sql(
 pub struct User {
     #[primary_key]
     identity: Identity,
-    name: Option<String>,
+    /// Unique handle used for mentions, which can only be set once
+    username: Option<String>,
+    // Not indexed: every unnamed user shares `None`, and SpacetimeDB
+    // can't filter on `Option` columns, so `set_username` scans for collisions.
+    username_normalized: Option<String>,
+    /// Freely changeable display name, shown in place of the username
+    nickname: Option<String>,
+    /// Display color as 0xRRGGBB, defaulting to one derived from the identity
+    color: Option<u32>,
+    /// When the user last changed their color, for `PROFILE_CHANGE_COOLDOWN`
+    color_changed_at: Option<Timestamp>,
+    /// When the user last set their username or nickname, for `PROFILE_CHANGE_COOLDOWN`
+    name_changed_at: Option<Timestamp>,
+    /// IANA timezone name, one of `KNOWN_TIMEZONES`
+    timezone: Option<String>,
+    /// Language tag such as `en` or `pt-BR`
+    locale: Option<String>,
+    /// An http(s) link to the user's profile picture
+    avatar_url: Option<String>,
     online: bool,
+    /// When the user's row was created; never changes afterwards
+    first_seen: Timestamp,
+    /// When the user was last known to be connected, refreshed by `heartbeat`
+    last_seen: Timestamp,
+    /// Set on disconnect; the user goes offline unless they reconnect before the grace period ends
+    pending_offline_at: Option<Timestamp>,
+    /// Number of consecutive UTC days, ending on `last_active_day`, the user sent a message
+    streak_days: u32,
+    /// Days since the Unix epoch of the user's most recent message
+    last_active_day: Option<u64>,
+    /// When the user's current `byte_budget_window_secs` window began
+    byte_window_start: Option<Timestamp>,
+    /// Message bytes the user has sent in the current window
+    bytes_in_window: u64,
+    /// Spam rejections since `violation_window_start`, reset when the window ends
+    violation_count: u32,
+    violation_window_start: Option<Timestamp>,
     authorized: bool,
+    /// Set once the user leaves the default channel, so re-authorizing them doesn't re-add them
+    left_default: bool,
+    /// Set once a moderator approves the user's first message under `moderate_first_message`
+    trusted: bool,
+    /// Do not disturb: mentions of the user don't create notifications
+    dnd: bool,
+    // Stored as flags rather than a `Role` so visibility filters can test them in SQL
+    moderator: bool,
+    admin: bool,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// What a user is allowed to do beyond chatting
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+}
+
+/// Reactions are visible wherever the message they're on is visible
+#[client_visibility_filter]
+const REACTION_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT r.*
+    FROM reaction r
+    JOIN message m ON m.id = r.message_id
+"#,
+);
+
+/// Reaction counts are visible wherever the message they're on is visible
+#[client_visibility_filter]
+const REACTION_COUNT_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT rc.*
+    FROM reaction_count rc
+    JOIN message m ON m.id = rc.message_id
+"#,
+);
+
+/// Attachments are visible wherever the message they're on is visible
+#[client_visibility_filter]
+const ATTACHMENT_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT a.*
+    FROM attachment a
+    JOIN message m ON m.id = a.message_id
+"#,
+);
+
+/// Links are visible wherever the message they're in is visible
+#[client_visibility_filter]
+const MESSAGE_LINK_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT l.*
+    FROM message_link l
+    JOIN message m ON m.id = l.message_id
+"#,
+);
+
+/// A URL found in a message's text
+#[table(name = message_link, public)]
+pub struct MessageLink {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    url: String,
+}
+
+/// Most links recorded in `message_link` per message
+const MAX_MESSAGE_LINKS: usize = 5;
+
+/// Seen-by rows are visible wherever the message they're about is visible
+#[client_visibility_filter]
+const SEEN_BY_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT s.*
+    FROM seen_by s
+    JOIN message m ON m.id = s.message_id
+"#,
+);
+
+/// A user having seen a message, recorded once per user and message by `mark_seen`
+#[table(name = seen_by, public)]
+pub struct SeenBy {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    #[index(btree)]
+    identity: Identity,
+    seen_at: Timestamp,
+}
+
+/// Pins are visible wherever the message they're on is visible
+#[client_visibility_filter]
+const PIN_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT p.*
+    FROM pin p
+    JOIN message m ON m.id = p.message_id
+"#,
+);
+
+/// A message pinned to the top of its channel, or of the room as a whole
+#[table(name = pin, public)]
+pub struct Pin {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[unique]
+    message_id: u64,
+    #[index(btree)]
+    channel_id: u64,
+    pinned_by: Identity,
+    pinned_at: Timestamp,
+}
+
+/// Most messages that can be pinned in one channel, or in the room as a whole
+const MAX_PINS: usize = 50;
+
+/// Metadata for a file uploaded elsewhere and attached to a message
+#[table(name = attachment, public)]
+pub struct Attachment {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    url: String,
+    mime_type: String,
+    size_bytes: u64,
+    filename: String,
+}
+
+/// An attachment as described by the client sending it
+#[derive(SpacetimeType)]
+pub struct AttachmentInput {
+    url: String,
+    mime_type: String,
+    size_bytes: u64,
+    filename: String,
+}
+
+const MAX_ATTACHMENTS: usize = 5;
+const MAX_ATTACHMENT_URL_LEN: usize = 2048;
+const MAX_ATTACHMENT_FILENAME_LEN: usize = 255;
+const ALLOWED_ATTACHMENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "video/mp4",
+    "audio/mpeg",
+    "application/pdf",
+    "text/plain",
+];
+
+/// One user's emoji reaction to a message
+#[table(name = reaction, public)]
+pub struct Reaction {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    identity: Identity,
+    emoji: String,
+    created_at: Timestamp,
+}
+
+/// How many users reacted to a message with a given emoji, so clients needn't count `reaction` rows
+#[table(name = reaction_count, public)]
+pub struct ReactionCount {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    emoji: String,
+    count: u32,
+}
+
+/// An emoji users may react with, public so clients can build their picker from it
+#[table(name = allowed_reaction, public)]
+pub struct AllowedReaction {
+    #[primary_key]
+    emoji: String,
+}
+
+/// The reactions available on a fresh database
+const DEFAULT_ALLOWED_REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🎉"];
+
+/// Maximum reaction length, in grapheme clusters
+const MAX_REACTION_LEN: usize = 16;
+
+/// An emoji that `:code:` in messages is replaced with, public so clients can offer completions
+#[table(name = shortcode, public)]
+pub struct Shortcode {
+    #[primary_key]
+    code: String,
+    emoji: String,
+}
+
+/// The shortcodes available on a fresh database
+const DEFAULT_SHORTCODES: [(&str, &str); 12] = [
+    ("smile", "😄"),
+    ("laughing", "😆"),
+    ("wink", "😉"),
+    ("cry", "😢"),
+    ("heart", "❤️"),
+    ("thumbsup", "👍"),
+    ("thumbsdown", "👎"),
+    ("fire", "🔥"),
+    ("tada", "🎉"),
+    ("eyes", "👀"),
+    ("thinking", "🤔"),
+    ("wave", "👋"),
+];
+
+/// Maximum shortcode length, in bytes
+const MAX_SHORTCODE_LEN: usize = 32;
+
+/// Moderators can see all reports
+#[client_visibility_filter]
+const MODERATOR_REPORT_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT r.*
+    FROM report r
+    JOIN user u ON u.dummy_join = r.dummy_join
+    WHERE u.moderator = true AND u.identity = :sender
+"#,
+);
+
+/// Admins can see all reports
+#[client_visibility_filter]
+const ADMIN_REPORT_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT r.*
+    FROM report r
+    JOIN user u ON u.dummy_join = r.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// Reporters can see the reports they filed
+#[client_visibility_filter]
+const OWN_REPORT_FILTER: Filter = Filter::Sql("SELECT * FROM report WHERE reporter = :sender");
+
+/// A user's complaint about a message, for moderators to review
+#[table(name = report, public)]
+pub struct Report {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    reporter: Identity,
+    reason: String,
+    created_at: Timestamp,
+    status: ReportStatus,
+    /// The moderator who resolved or dismissed the report
+    handled_by: Option<Identity>,
+    dummy_join: bool, // workaround join restriction
+}
+
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportStatus {
+    /// Awaiting review
+    Open,
+    /// A moderator agreed and acted on it
+    Resolved,
+    /// A moderator decided no action was needed
+    Dismissed,
+}
+
+/// Maximum report reason length, in grapheme clusters
+const MAX_REPORT_REASON_LEN: usize = 500;
+
+/// Authors can see their own pending messages
+#[client_visibility_filter]
+const OWN_PENDING_MESSAGE_FILTER: Filter =
+    Filter::Sql("SELECT * FROM pending_message WHERE sender = :sender");
+
+/// Moderators can see all pending messages
+#[client_visibility_filter]
+const MODERATOR_PENDING_MESSAGE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT p.*
+    FROM pending_message p
+    JOIN user u ON u.dummy_join = p.dummy_join
+    WHERE u.moderator = true AND u.identity = :sender
+"#,
+);
+
+/// Admins can see all pending messages
+#[client_visibility_filter]
+const ADMIN_PENDING_MESSAGE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT p.*
+    FROM pending_message p
+    JOIN user u ON u.dummy_join = p.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// A new user's first message, held for a moderator to approve under `moderate_first_message`.
+/// Approved messages move to `message`; rejected ones stay so the author can see why.
+#[table(name = pending_message, public)]
+pub struct PendingMessage {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    sender: Identity,
+    #[index(btree)]
+    channel_id: u64,
+    sent: Timestamp,
+    text: String,
+    kind: MessageKind,
+    status: PendingStatus,
+    /// Why a moderator rejected the message
+    reject_reason: Option<String>,
     dummy_join: bool, // workaround join restriction
 }
 
-#[table(name = message, public)]
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PendingStatus {
+    /// Awaiting approval
+    Pending,
+    Rejected,
+}
+
+/// A client can only see their own search results
+#[client_visibility_filter]
+const SEARCH_RESULT_FILTER: Filter =
+    Filter::Sql("SELECT * FROM search_result WHERE owner = :sender");
+
+/// A client can only see their own user search results
+#[client_visibility_filter]
+const USER_SEARCH_RESULT_FILTER: Filter =
+    Filter::Sql("SELECT * FROM user_search_result WHERE owner = :sender");
+
+/// A client can only see the history they loaded themselves
+#[client_visibility_filter]
+const LOADED_MESSAGE_FILTER: Filter =
+    Filter::Sql("SELECT * FROM loaded_message WHERE owner = :sender");
+
+#[table(
+    name = message,
+    public,
+    index(name = channel_seq, btree(columns = [channel_id, seq]))
+)]
 pub struct Message {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    /// The channel the message was sent to, or `GLOBAL_CHANNEL_ID` for the room as a whole
+    #[index(btree)]
+    channel_id: u64,
+    #[index(btree)]
     sender: Identity,
+    // Backs time-ordered subscription queries. `Timestamp` isn't a filterable
+    // key in the module bindings, so reducers still scan when filtering by time.
+    #[index(btree)]
     sent: Timestamp,
     text: String,
+    edited_at: Option<Timestamp>,
+    /// Set once enough users report the message; only admins can see hidden messages
+    hidden: bool,
+    /// When `expire_messages` deletes the message, for messages sent with `send_ephemeral_message`
+    expires_at: Option<Timestamp>,
+    /// For forwarded messages, the original author, even across chained forwards
+    forwarded_from: Option<Identity>,
+    /// For forwarded messages, the id of the message first forwarded
+    forwarded_message_id: Option<u64>,
+    /// Position in the channel's message sequence, from 1; 0 for messages to the room as a whole
+    seq: u64,
+    kind: MessageKind,
     dummy_join: bool, // workaround join restriction
 }
 
-#[spacetimedb::reducer(init)]
-/// Called when the module is initially published
-pub fn init(ctx: &ReducerContext) {
-    // Create an initial authorized user
-    let admin_hex_id = "c2009546b62e8bf62a4b1387664842c54821f56214e6e6897021091f3f5a053f";
-    let identity = Identity::from_hex(admin_hex_id).expect("Invalid hex string");
-    ctx.db.user().insert(User {
-        name: None,
-        identity: identity,
-        online: true,
-        authorized: true,
-        dummy_join: true,
-    });
+/// A client can only see their own read markers
+#[client_visibility_filter]
+const READ_MARKER_FILTER: Filter =
+    Filter::Sql("SELECT * FROM read_marker WHERE identity = :sender");
+
+/// The latest message a user has read in a channel, or in the room as a whole.
+/// Messages with higher ids are unread.
+#[table(name = read_marker, public)]
+pub struct ReadMarker {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity,
+    /// `GLOBAL_CHANNEL_ID` for the room as a whole
+    channel_id: u64,
+    last_read_message_id: u64,
 }
 
-#[reducer]
-/// Clients invoke this reducer to set their user names.
-pub fn set_name(ctx: &ReducerContext, name: String) -> Result<(), String> {
-    validate_identity(ctx)?;
+/// A client can only see the unread counts they loaded themselves
+#[client_visibility_filter]
+const UNREAD_COUNT_FILTER: Filter = Filter::Sql("SELECT * FROM unread_count WHERE owner = :sender");
 
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
-        let name = validate_name(name)?;
-        ctx.db.user().identity().update(User {
-            name: Some(name),
-            ..user
-        });
-        Ok(())
-    } else {
-        Err("Cannot set name for unknown user".to_string())
-    }
+/// How many unread messages the owner had in a channel at their last `load_unread_counts` call
+#[table(name = unread_count, public)]
+pub struct UnreadCount {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    owner: Identity,
+    /// `GLOBAL_CHANNEL_ID` for the room as a whole
+    channel_id: u64,
+    unread: u64,
 }
 
-fn validate_identity(ctx: &ReducerContext) -> Result<(), String> {
-    match ctx.db.user().identity().find(ctx.sender) {
-        Some(user) if user.authorized => Ok(()),
-        Some(_) => Err("Unauthorized user attempted to perform an action".to_string()),
-        None => Err("Validation failed: Unknown user".to_string()),
-    }
+/// How clients should render a message
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    Normal,
+    /// Sent with `/me`, shown as something the sender did, e.g. "* alice waves"
+    Action,
+    /// Posted by the module itself
+    System,
 }
 
-/// Takes a name and checks if it's acceptable as a user's name.
-fn validate_name(name: String) -> Result<String, String> {
-    if name.is_empty() {
-        Err("Names must not be empty".to_string())
-    } else {
-        Ok(name)
-    }
+/// A message sent with `send_message_idempotent`, so a retry with the same key isn't posted twice
+#[table(name = recent_send_key)]
+pub struct RecentSendKey {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    sender: Identity,
+    key: String,
+    message_id: u64,
+    created_at: Timestamp,
 }
 
-#[reducer]
-/// Clients invoke this reducer to send messages.
-pub fn send_message(ctx: &ReducerContext, text: String) -> Result<(), String> {
-    validate_identity(ctx)?;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
 
-    let text = validate_message(text)?;
-    log::info!("{}", text);
-    ctx.db.message().insert(Message {
-        sender: ctx.sender,
-        text,
-        sent: ctx.timestamp,
-        dummy_join: true,
-    });
-    Ok(())
+/// How long a send key prevents a repeat
+const SEND_KEY_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Runs `prune_send_keys` periodically
+#[table(name = send_key_sweep_schedule, scheduled(prune_send_keys))]
+pub struct SendKeySweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
 }
 
-/// Takes a message's text and checks if it's acceptable to send.
-fn validate_message(text: String) -> Result<String, String> {
-    if text.is_empty() {
-        Err("Messages must not be empty".to_string())
-    } else {
-        Ok(text)
-    }
+const SEND_KEY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message matched by the owner's most recent `search_messages` call
+#[table(name = search_result, public)]
+pub struct SearchResult {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    owner: Identity,
+    message_id: u64,
+    channel_id: u64,
+    sender: Identity,
+    sent: Timestamp,
+    text: String,
 }
 
-#[reducer(client_connected)]
-// Called when a client connects to a SpacetimeDB database server
-pub fn client_connected(ctx: &ReducerContext) {
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
-        // If this is a returning user, i.e. we already have a `User` with this `Identity`,
-        // set `online: true`, but leave `name` and `identity` unchanged.
-        ctx.db.user().identity().update(User {
-            online: true,
-            ..user
-        });
-    } else {
-        // If this is a new user, create a `User` row for the `Identity`,
-        // which is online, but hasn't set a name.
-        ctx.db.user().insert(User {
-            name: None,
-            identity: ctx.sender,
-            online: true,
-            authorized: false,
-            dummy_join: true,
-        });
-    }
+/// A user matched by the owner's most recent `search_users` call
+#[table(name = user_search_result, public)]
+pub struct UserSearchResult {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    owner: Identity,
+    identity: Identity,
+    username: String,
+    nickname: Option<String>,
+}
 
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
-        if !user.authorized {
-            log::warn!("Unauthorized user connected: {:?}", user.identity.to_hex());
-        }
-    }
+const MAX_USER_SEARCH_RESULTS: usize = 20;
+
+/// A client can only see their own name checks
+#[client_visibility_filter]
+const NAME_CHECK_RESULT_FILTER: Filter =
+    Filter::Sql("SELECT * FROM name_check_result WHERE owner = :sender");
+
+/// The outcome of the owner's most recent `check_name` call
+#[table(name = name_check_result, public)]
+pub struct NameCheckResult {
+    #[primary_key]
+    owner: Identity,
+    name: String,
+    available: bool,
+    /// The error `set_username` would fail with
+    error: Option<String>,
+    expires_at: Timestamp,
 }
 
-#[reducer(client_disconnected)]
-// Called when a client disconnects from SpacetimeDB database server
-pub fn identity_disconnected(ctx: &ReducerContext) {
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
-        ctx.db.user().identity().update(User {
-            online: false,
-            ..user
-        });
-    } else {
-        // This branch should be unreachable,
-        // as it doesn't make sense for a client to disconnect without connecting first.
-        log::warn!(
-            "Disconnect event for unknown user with identity {:?}",
-            ctx.sender
+/// How long a name check result is kept
+const NAME_CHECK_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Runs `expire_name_checks` periodically
+#[table(name = name_check_sweep_schedule, scheduled(expire_name_checks))]
+pub struct NameCheckSweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+const NAME_CHECK_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A client can only see the profile they loaded themselves
+#[client_visibility_filter]
+const PROFILE_VIEW_FILTER: Filter = Filter::Sql("SELECT * FROM profile_view WHERE owner = :sender");
+
+/// The public parts of the user in the owner's most recent `load_profile` call
+#[table(name = profile_view, public)]
+pub struct ProfileView {
+    #[primary_key]
+    owner: Identity,
+    identity: Identity,
+    username: Option<String>,
+    nickname: Option<String>,
+    color: Option<u32>,
+    avatar_url: Option<String>,
+    online: bool,
+    first_seen: Timestamp,
+    account_age_secs: u64,
+}
+
+/// A client can only see the channel rosters they loaded themselves
+#[client_visibility_filter]
+const LOADED_CHANNEL_MEMBER_FILTER: Filter =
+    Filter::Sql("SELECT * FROM loaded_channel_member WHERE owner = :sender");
+
+/// A member of the channel in the owner's most recent `load_channel_members` call
+#[table(name = loaded_channel_member, public)]
+pub struct LoadedChannelMember {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    owner: Identity,
+    channel_id: u64,
+    identity: Identity,
+    /// The member's channel nickname, falling back to their global name
+    name: String,
+    online: bool,
+    is_moderator: bool,
+}
+
+/// A client can only see conversations they're a member of
+#[client_visibility_filter]
+const CONVERSATION_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT c.*
+    FROM conversation c
+    JOIN conversation_member m ON m.conversation_id = c.id
+    WHERE m.identity = :sender
+"#,
+);
+
+/// A client can only see their own memberships; the other participants are listed on the conversation
+#[client_visibility_filter]
+const CONVERSATION_MEMBER_FILTER: Filter =
+    Filter::Sql("SELECT * FROM conversation_member WHERE identity = :sender");
+
+/// A client can only see messages in conversations they're a member of
+#[client_visibility_filter]
+const CONVERSATION_MESSAGE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT cm.*
+    FROM conversation_message cm
+    JOIN conversation_member m ON m.conversation_id = cm.conversation_id
+    WHERE m.identity = :sender
+"#,
+);
+
+/// A client can always see their own conversation read markers
+#[client_visibility_filter]
+const OWN_CONVERSATION_READ_MARKER_FILTER: Filter =
+    Filter::Sql("SELECT * FROM conversation_read_marker WHERE identity = :sender");
+
+/// Other members' read markers are visible within a conversation, unless they hide read receipts
+#[client_visibility_filter]
+const CONVERSATION_READ_MARKER_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT r.*
+    FROM conversation_read_marker r
+    JOIN conversation_member m ON m.conversation_id = r.conversation_id
+    WHERE m.identity = :sender AND r.hidden = false
+"#,
+);
+
+/// A private group conversation
+#[table(name = conversation, public)]
+pub struct Conversation {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    creator: Identity,
+    /// Every member, including the creator
+    participants: Vec<Identity>,
+    created_at: Timestamp,
+    /// When the latest message was sent, or `created_at` if there are none yet
+    last_message_at: Timestamp,
+}
+
+/// The conversation two users have with each other alone. `user_a` is the lower of the two
+/// identities, so each pair has one row.
+#[table(name = direct_conversation)]
+pub struct DirectConversation {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    user_a: Identity,
+    user_b: Identity,
+    #[unique]
+    conversation_id: u64,
+}
+
+#[table(name = conversation_member, public)]
+pub struct ConversationMember {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    conversation_id: u64,
+    #[index(btree)]
+    identity: Identity,
+}
+
+/// The latest conversation message a member has read
+#[table(name = conversation_read_marker, public)]
+pub struct ConversationReadMarker {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    conversation_id: u64,
+    #[index(btree)]
+    identity: Identity,
+    last_read_message_id: u64,
+    /// Mirrors the member's `hide_read_receipts` preference, so other members can't see it
+    hidden: bool,
+}
+
+#[table(name = conversation_message, public)]
+pub struct ConversationMessage {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    conversation_id: u64,
+    sender: Identity,
+    sent: Timestamp,
+    text: String,
+}
+
+/// A client can only see their own channel memberships
+#[client_visibility_filter]
+const CHANNEL_MEMBER_FILTER: Filter =
+    Filter::Sql("SELECT * FROM channel_member WHERE identity = :sender");
+
+/// Public channels are listed to everyone
+#[client_visibility_filter]
+const PUBLIC_CHANNEL_FILTER: Filter =
+    Filter::Sql("SELECT * FROM channel WHERE invite_only = false");
+
+/// Invite-only channels are listed to their members
+#[client_visibility_filter]
+const MEMBER_CHANNEL_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT c.*
+    FROM channel c
+    JOIN channel_member m ON m.channel_id = c.id
+    WHERE m.identity = :sender
+"#,
+);
+
+/// Invite-only channels are listed to users invited to them
+#[client_visibility_filter]
+const INVITED_CHANNEL_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT c.*
+    FROM channel c
+    JOIN channel_invite i ON i.channel_id = c.id
+    WHERE i.invitee = :sender
+"#,
+);
+
+/// A named chat channel that authorized users can join
+#[table(name = channel, public)]
+pub struct Channel {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[unique]
+    name: String,
+    creator: Identity,
+    created_at: Timestamp,
+    topic: Option<String>,
+    // Stored as a flag rather than a `ChannelVisibility` so visibility filters can test it in SQL
+    invite_only: bool,
+    /// Archived channels keep their history readable but accept no new messages or members
+    archived: bool,
+    /// The sidebar category the channel is listed under, if any
+    category_id: Option<u64>,
+    /// Order within the channel's category (or among uncategorized channels), from 0 with no gaps
+    position: u32,
+    /// The `seq` the channel's next message gets
+    next_seq: u64,
+}
+
+/// A sidebar group of channels
+#[table(name = category, public)]
+pub struct Category {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    name: String,
+    /// Order in the sidebar, from 0
+    position: u32,
+}
+
+/// Who may join a channel
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelVisibility {
+    /// Any authorized user can join
+    Public,
+    /// Users can only join with an invite from a member
+    InviteOnly,
+}
+
+/// A client can see the channel invites they received
+#[client_visibility_filter]
+const RECEIVED_CHANNEL_INVITE_FILTER: Filter =
+    Filter::Sql("SELECT * FROM channel_invite WHERE invitee = :sender");
+
+/// A client can see the channel invites they sent
+#[client_visibility_filter]
+const SENT_CHANNEL_INVITE_FILTER: Filter =
+    Filter::Sql("SELECT * FROM channel_invite WHERE inviter = :sender");
+
+/// A pending invitation to join an invite-only channel
+#[table(name = channel_invite, public)]
+pub struct ChannelInvite {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    channel_id: u64,
+    #[index(btree)]
+    invitee: Identity,
+    inviter: Identity,
+    created_at: Timestamp,
+}
+
+/// Runs `sweep_channel_invites` periodically
+#[table(name = channel_invite_sweep_schedule, scheduled(sweep_channel_invites))]
+pub struct ChannelInviteSweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// How often `sweep_channel_invites` runs
+const CHANNEL_INVITE_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long an unanswered channel invite stays valid
+const CHANNEL_INVITE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[table(name = channel_member, public)]
+pub struct ChannelMember {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    channel_id: u64,
+    #[index(btree)]
+    identity: Identity,
+    joined_at: Timestamp,
+    /// Channel moderators can delete messages, set the topic and kick members in this channel
+    is_moderator: bool,
+    /// Shown in place of the member's global name in this channel; unique within the channel
+    nickname: Option<String>,
+}
+
+/// Only admins can see the audit log
+#[client_visibility_filter]
+const AUDIT_LOG_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT a.*
+    FROM audit_log a
+    JOIN user u ON u.dummy_join = a.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// A record of a moderation action, for later review
+#[table(name = audit_log, public)]
+pub struct AuditLog {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    actor: Identity,
+    /// A short snake_case name for what was done, e.g. `kick_member`
+    action: String,
+    target: Option<Identity>,
+    /// The channel the action was scoped to, if any
+    channel_id: Option<u64>,
+    created_at: Timestamp,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// Only admins can see message exports
+#[client_visibility_filter]
+const EXPORT_BLOB_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT e.*
+    FROM export_blob e
+    JOIN user u ON u.dummy_join = e.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// A JSON snapshot of every message, produced by `export_messages`
+#[table(name = export_blob, public)]
+pub struct ExportBlob {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    /// A JSON array of `ExportedMessage`s
+    json: String,
+    created_at: Timestamp,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// The serialized form of a `Message` in an export
+#[derive(Serialize, Deserialize)]
+struct ExportedMessage {
+    id: u64,
+    channel_id: u64,
+    /// The sender's identity, in hex
+    sender: String,
+    /// Microseconds since the Unix epoch
+    sent: i64,
+    text: String,
+    edited_at: Option<i64>,
+    hidden: bool,
+    #[serde(default)]
+    expires_at: Option<i64>,
+}
+
+impl From<&Message> for ExportedMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            id: message.id,
+            channel_id: message.channel_id,
+            sender: message.sender.to_hex().to_string(),
+            sent: message.sent.to_micros_since_unix_epoch(),
+            text: message.text.clone(),
+            edited_at: message
+                .edited_at
+                .map(|edited_at| edited_at.to_micros_since_unix_epoch()),
+            hidden: message.hidden,
+            expires_at: message
+                .expires_at
+                .map(|expires_at| expires_at.to_micros_since_unix_epoch()),
+        }
+    }
+}
+
+/// The sender of messages whose author deleted their account
+const DELETED_USER: Identity = Identity::ZERO;
+
+/// The `channel_id` of messages sent to the room as a whole rather than to a channel
+const GLOBAL_CHANNEL_ID: u64 = 0;
+
+/// Maximum channel topic length, in grapheme clusters
+const MAX_TOPIC_LEN: usize = 200;
+
+/// A client can only see friend requests they sent
+#[client_visibility_filter]
+const SENT_FRIEND_REQUEST_FILTER: Filter =
+    Filter::Sql(r#"SELECT * FROM friend_request WHERE "from" = :sender"#);
+
+/// A client can only see friend requests sent to them
+#[client_visibility_filter]
+const RECEIVED_FRIEND_REQUEST_FILTER: Filter =
+    Filter::Sql(r#"SELECT * FROM friend_request WHERE "to" = :sender"#);
+
+/// A client can only see friendships they're part of
+#[client_visibility_filter]
+const FRIENDSHIP_A_FILTER: Filter = Filter::Sql("SELECT * FROM friendship WHERE a = :sender");
+
+/// A client can only see friendships they're part of
+#[client_visibility_filter]
+const FRIENDSHIP_B_FILTER: Filter = Filter::Sql("SELECT * FROM friendship WHERE b = :sender");
+
+#[table(name = friend_request, public)]
+pub struct FriendRequest {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    from: Identity,
+    #[index(btree)]
+    to: Identity,
+    created_at: Timestamp,
+}
+
+/// A mutual friendship, stored once per pair with `a < b`
+#[table(name = friendship, public)]
+pub struct Friendship {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    a: Identity,
+    #[index(btree)]
+    b: Identity,
+    since: Timestamp,
+}
+
+/// One user blocking another
+#[table(name = block, public)]
+pub struct Block {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    blocker: Identity,
+    #[index(btree)]
+    blocked: Identity,
+    created_at: Timestamp,
+}
+
+/// A client can only see the blocks they made, so nobody can tell who blocked them
+#[client_visibility_filter]
+const BLOCK_FILTER: Filter = Filter::Sql("SELECT * FROM block WHERE blocker = :sender");
+
+/// Typing in the room as a whole is visible to authorized clients
+#[client_visibility_filter]
+const GLOBAL_TYPING_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT t.*
+    FROM typing_indicator t
+    JOIN user u ON u.dummy_join = t.dummy_join
+    WHERE u.authorized = true AND u.identity = :sender
+        AND t.channel_id = 0 AND t.conversation_id = 0
+"#,
+);
+
+/// Typing in a channel is only visible to its members
+#[client_visibility_filter]
+const CHANNEL_TYPING_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT t.*
+    FROM typing_indicator t
+    JOIN channel_member m ON m.channel_id = t.channel_id
+    WHERE m.identity = :sender
+"#,
+);
+
+/// Typing in a conversation is only visible to its members
+#[client_visibility_filter]
+const CONVERSATION_TYPING_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT t.*
+    FROM typing_indicator t
+    JOIN conversation_member m ON m.conversation_id = t.conversation_id
+    WHERE m.identity = :sender
+"#,
+);
+
+/// Shows that a user is typing, until `expires_at` or until they stop
+#[table(name = typing_indicator, public)]
+pub struct TypingIndicator {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity,
+    /// `GLOBAL_CHANNEL_ID` unless typing in a channel
+    channel_id: u64,
+    /// 0 unless typing in a conversation
+    conversation_id: u64,
+    expires_at: Timestamp,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// Where a user is typing
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TypingScope {
+    /// The room as a whole
+    Global,
+    Channel(u64),
+    Conversation(u64),
+}
+
+/// Runs `expire_typing_indicators` periodically
+#[table(name = typing_sweep_schedule, scheduled(expire_typing_indicators))]
+pub struct TypingSweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// Runs `expire_messages` periodically
+#[table(name = message_expiry_schedule, scheduled(expire_messages))]
+pub struct MessageExpirySchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// An ephemeral message deleted by `expire_messages`, remembered so that looking it up
+/// still reports that it expired
+#[table(name = expired_message)]
+pub struct ExpiredMessage {
+    #[primary_key]
+    message_id: u64,
+    channel_id: u64,
+    expired_at: Timestamp,
+}
+
+/// How often `expire_messages` runs
+const MESSAGE_EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
+const MIN_MESSAGE_TTL_SECS: u64 = 10;
+const MAX_MESSAGE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// How long a typing indicator lasts unless refreshed by another `start_typing`
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// How often `expire_typing_indicators` runs
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Only admins can see the allowlist
+#[client_visibility_filter]
+const ALLOWLIST_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT a.*
+    FROM allowlist a
+    JOIN user u ON u.dummy_join = a.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// Identities that are authorized automatically the first time they connect
+#[table(name = allowlist, public)]
+pub struct Allowlist {
+    #[primary_key]
+    identity: Identity,
+    added_by: Identity,
+    added_at: Timestamp,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// Tunable server settings, stored as a single row with id `CONFIG_ID`
+#[table(name = config, public)]
+pub struct Config {
+    #[primary_key]
+    id: u32,
+    /// Maximum name length, in grapheme clusters
+    max_name_len: u32,
+    /// Maximum message length, in grapheme clusters
+    max_message_len: u32,
+    /// Whether `send_message` rejects repeats of the sender's previous message
+    duplicate_check_enabled: bool,
+    /// How long a repeated message counts as a duplicate, in seconds
+    duplicate_window_secs: u64,
+    /// Maximum number of distinct users a single message may mention
+    max_mentions: u32,
+    /// How long after sending a message its author may still edit it, in minutes; 0 is unlimited
+    edit_window_mins: u64,
+    /// How long after sending a message its author may still delete it, in minutes; 0 is unlimited
+    delete_window_mins: u64,
+    /// Maximum number of members in a group conversation, including its creator
+    max_conversation_participants: u32,
+    /// How long after disconnecting a user is shown as offline, in seconds
+    offline_grace_secs: u64,
+    /// Number of distinct reporters after which a message is hidden from everyone but admins
+    report_hide_threshold: u32,
+    /// The channel newly authorized users join automatically, which can't be archived.
+    /// `init` seeds it as "general".
+    default_channel_id: u64,
+    /// Largest file that may be attached to a message, in bytes
+    max_attachment_bytes: u64,
+    /// Most XP a user can earn from messages in one hour
+    xp_hourly_cap: u32,
+    /// How long a never-authorized, unnamed user may stay offline before being pruned, in days
+    prune_inactive_after_days: u64,
+    /// Whether `delete_my_account` keeps the user's messages, attributed to `DELETED_USER`,
+    /// rather than deleting them
+    anonymize_deleted_messages: bool,
+    /// Length of the window `byte_budget_max_bytes` applies to, in seconds
+    byte_budget_window_secs: u64,
+    /// Most message bytes a user may send per window
+    byte_budget_max_bytes: u64,
+    /// How old an account must be before it can send messages, in seconds; moderators are exempt
+    min_account_age_secs: u64,
+    /// Whether regular users may post http(s) links
+    allow_links: bool,
+    /// Whether unauthorized users can read (but not post to) the room as a whole
+    guest_read_access: bool,
+    /// Sent privately to each new user when they first connect
+    welcome_message: String,
+    /// Whether everyone can see `user_stats`, rather than just admins and the user themselves
+    user_stats_public: bool,
+    /// Whether a new user's first message must be approved by a moderator before it's posted
+    moderate_first_message: bool,
+    /// Who published the module, and so may run `setup_admins`
+    publisher: Identity,
+    /// Set once `setup_admins` has run
+    setup_complete: bool,
+    dummy_join: bool, // workaround join restriction
+}
+
+const CONFIG_ID: u32 = 0;
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            id: CONFIG_ID,
+            max_name_len: 32,
+            max_message_len: 2000,
+            duplicate_check_enabled: true,
+            duplicate_window_secs: 30,
+            max_mentions: 5,
+            edit_window_mins: 10,
+            delete_window_mins: 60,
+            max_conversation_participants: 10,
+            offline_grace_secs: 15,
+            report_hide_threshold: 3,
+            default_channel_id: GLOBAL_CHANNEL_ID,
+            max_attachment_bytes: 25 * 1024 * 1024,
+            xp_hourly_cap: 30,
+            prune_inactive_after_days: 30,
+            anonymize_deleted_messages: true,
+            byte_budget_window_secs: 60,
+            byte_budget_max_bytes: 16 * 1024,
+            min_account_age_secs: 0,
+            allow_links: true,
+            guest_read_access: false,
+            welcome_message: "Welcome! Set a name to start chatting.".to_string(),
+            user_stats_public: true,
+            moderate_first_message: false,
+            publisher: Identity::ZERO,
+            setup_complete: false,
+            dummy_join: true,
+        }
+    }
+}
+
+const MAX_WELCOME_MESSAGE_LEN: usize = 500;
+
+/// The welcome message a user received when they first connected
+#[table(name = welcome, public)]
+pub struct Welcome {
+    #[primary_key]
+    identity: Identity,
+    text: String,
+    sent: Timestamp,
+}
+
+/// A client can only see their own welcome message
+#[client_visibility_filter]
+const WELCOME_FILTER: Filter = Filter::Sql("SELECT * FROM welcome WHERE identity = :sender");
+
+/// A client can see whether they're muted
+#[client_visibility_filter]
+const OWN_MUTE_FILTER: Filter = Filter::Sql("SELECT * FROM mute WHERE identity = :sender");
+
+/// Moderators can see all mutes
+#[client_visibility_filter]
+const MODERATOR_MUTE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT m.*
+    FROM mute m
+    JOIN user u ON u.dummy_join = m.dummy_join
+    WHERE u.moderator = true AND u.identity = :sender
+"#,
+);
+
+/// Admins can see all mutes
+#[client_visibility_filter]
+const ADMIN_MUTE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT m.*
+    FROM mute m
+    JOIN user u ON u.dummy_join = m.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// A user who can't post messages until `until`. The row is kept after the mute ends
+/// so that repeat offenders get longer mutes.
+#[table(name = mute, public)]
+pub struct Mute {
+    #[primary_key]
+    identity: Identity,
+    until: Timestamp,
+    /// How many times the user has been muted automatically
+    auto_mutes: u32,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// Spam rejections within `SPAM_VIOLATION_WINDOW` that trigger an automatic mute
+const SPAM_VIOLATION_LIMIT: u32 = 5;
+const SPAM_VIOLATION_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// The first automatic mute's length, doubled for each one after it
+const AUTO_MUTE_BASE: Duration = Duration::from_secs(5 * 60);
+const AUTO_MUTE_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// Per-sender bookkeeping for the spam checks in `send_message`
+#[table(name = send_state)]
+pub struct SendState {
+    #[primary_key]
+    identity: Identity,
+    last_text_hash: Hash,
+    last_sent: Timestamp,
+}
+
+/// A client can only see their own rejections
+#[client_visibility_filter]
+const SEND_REJECTION_FILTER: Filter =
+    Filter::Sql("SELECT * FROM send_rejection WHERE identity = :sender");
+
+/// Why the sender's latest message was dropped as spam, or their scheduled message refused.
+/// Cleared by their next successful send.
+#[table(name = send_rejection, public)]
+pub struct SendRejection {
+    #[primary_key]
+    identity: Identity,
+    reason: String,
+    rejected_at: Timestamp,
+}
+
+/// A client can always see their own stats
+#[client_visibility_filter]
+const OWN_USER_STATS_FILTER: Filter =
+    Filter::Sql("SELECT * FROM user_stats WHERE identity = :sender");
+
+/// Admins can always see everyone's stats
+#[client_visibility_filter]
+const ADMIN_USER_STATS_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT s.*
+    FROM user_stats s
+    JOIN user u ON u.dummy_join = s.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// Everyone can see everyone's stats unless the config keeps them private
+#[client_visibility_filter]
+const PUBLIC_USER_STATS_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT s.*
+    FROM user_stats s
+    JOIN config c ON c.dummy_join = s.dummy_join
+    WHERE c.user_stats_public = true
+"#,
+);
+
+/// Lifetime sending totals for a user, created on their first message
+#[table(name = user_stats, public)]
+pub struct UserStats {
+    #[primary_key]
+    identity: Identity,
+    messages_sent: u64,
+    /// Total length of sent messages, in grapheme clusters
+    characters_sent: u64,
+    /// Reactions other users have left on this user's messages
+    reactions_received: u64,
+    first_message_at: Timestamp,
+    last_message_at: Timestamp,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// A user's participation XP, earned by sending messages
+#[table(name = progression, public)]
+pub struct Progression {
+    #[primary_key]
+    identity: Identity,
+    xp: u64,
+    /// 1-based level derived from `xp` via `LEVEL_THRESHOLDS`
+    level: u32,
+    /// Hours since the Unix epoch of the hour `xp_this_hour` counts
+    hour: u64,
+    /// XP earned from messages during `hour`, capped at `xp_hourly_cap`
+    xp_this_hour: u32,
+}
+
+/// The XP needed to reach each level, starting at level 1
+const LEVEL_THRESHOLDS: [u64; 10] = [0, 10, 50, 150, 400, 1_000, 2_500, 5_000, 10_000, 25_000];
+
+const MICROS_PER_HOUR: i64 = 60 * 60 * 1_000_000;
+
+/// A kind of badge admins can grant, shown next to names
+#[table(name = badge, public)]
+pub struct Badge {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[unique]
+    name: String,
+    icon: String,
+    description: String,
+}
+
+/// A badge held by a user
+#[table(name = user_badge, public)]
+pub struct UserBadge {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity,
+    #[index(btree)]
+    badge_id: u64,
+    granted_at: Timestamp,
+    granted_by: Identity,
+}
+
+/// Maximum badge name length, in grapheme clusters
+const MAX_BADGE_NAME_LEN: usize = 32;
+
+/// Maximum badge icon or description length, in grapheme clusters
+const MAX_BADGE_TEXT_LEN: usize = 200;
+
+/// A message copied into the owner's view by a history-loading reducer
+#[table(name = loaded_message, public)]
+pub struct LoadedMessage {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    owner: Identity,
+    message_id: u64,
+    channel_id: u64,
+    sender: Identity,
+    sent: Timestamp,
+    text: String,
+}
+
+/// Tells a user they were mentioned in a message
+#[table(name = notification, public)]
+pub struct Notification {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    recipient: Identity,
+    #[index(btree)]
+    message_id: u64,
+    channel_id: u64,
+    sender: Identity,
+    created: Timestamp,
+    /// Set once the recipient has loaded the notification with `load_my_notifications`
+    delivered: bool,
+}
+
+/// A notification copied into the owner's view by `load_my_notifications`
+#[table(name = loaded_notification, public)]
+pub struct LoadedNotification {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    owner: Identity,
+    notification_id: u64,
+    message_id: u64,
+    channel_id: u64,
+    sender: Identity,
+    created: Timestamp,
+}
+
+/// A client can only see the notifications they loaded themselves
+#[client_visibility_filter]
+const LOADED_NOTIFICATION_FILTER: Filter =
+    Filter::Sql("SELECT * FROM loaded_notification WHERE owner = :sender");
+
+/// Runs `prune_notifications` daily
+#[table(name = notification_prune_schedule, scheduled(prune_notifications))]
+pub struct NotificationPruneSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// How often `prune_notifications` runs
+const NOTIFICATION_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long delivered notifications are kept
+const DELIVERED_NOTIFICATION_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A client can only see their own notifications
+#[client_visibility_filter]
+const NOTIFICATION_FILTER: Filter =
+    Filter::Sql("SELECT * FROM notification WHERE recipient = :sender");
+
+/// A user being @mentioned in a message
+#[table(name = mention, public)]
+pub struct Mention {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    #[index(btree)]
+    mentioned: Identity,
+    mentioner: Identity,
+    created_at: Timestamp,
+}
+
+/// A client can only see the mentions of themselves
+#[client_visibility_filter]
+const MENTION_FILTER: Filter = Filter::Sql("SELECT * FROM mention WHERE mentioned = :sender");
+
+/// What a user wants to be notified about, created with defaults when they first connect
+#[table(name = notification_prefs, public)]
+pub struct NotificationPrefs {
+    #[primary_key]
+    identity: Identity,
+    notify_on_mention: bool,
+    notify_on_dm: bool,
+    notify_on_all_messages: bool,
+    /// Keeps the user's conversation read markers from other members
+    hide_read_receipts: bool,
+}
+
+/// A client can only see their own notification preferences
+#[client_visibility_filter]
+const NOTIFICATION_PREFS_FILTER: Filter =
+    Filter::Sql("SELECT * FROM notification_prefs WHERE identity = :sender");
+
+impl NotificationPrefs {
+    fn defaults(identity: Identity) -> Self {
+        Self {
+            identity,
+            notify_on_mention: true,
+            notify_on_dm: true,
+            notify_on_all_messages: false,
+            hide_read_receipts: false,
+        }
+    }
+}
+
+/// Message activity for one UTC day
+#[table(name = daily_stats, public)]
+pub struct DailyStats {
+    #[primary_key]
+    /// Days since the Unix epoch
+    day: u64,
+    messages_count: u64,
+    active_users: u64,
+}
+
+/// Runs `rollup_daily_stats` shortly after each UTC midnight
+#[table(name = daily_stats_schedule, scheduled(rollup_daily_stats))]
+pub struct DailyStatsSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// Runs `prune_inactive_users` daily
+#[table(name = prune_users_schedule, scheduled(prune_inactive_users))]
+pub struct PruneUsersSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// How often `prune_inactive_users` runs
+const PRUNE_USERS_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Only admins can see sessions
+#[client_visibility_filter]
+const SESSION_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT s.*
+    FROM session s
+    JOIN user u ON u.dummy_join = s.dummy_join
+    WHERE u.admin = true AND u.identity = :sender
+"#,
+);
+
+/// One connection of a client, from connect to disconnect
+#[table(name = session, public)]
+pub struct Session {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity,
+    connected_at: Timestamp,
+    /// `None` while the client is still connected
+    disconnected_at: Option<Timestamp>,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// Runs `trim_sessions` daily
+#[table(name = session_trim_schedule, scheduled(trim_sessions))]
+pub struct SessionTrimSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// How often `trim_sessions` runs
+const SESSION_TRIM_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long sessions are kept after they begin
+const SESSION_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A message waiting to be posted to the room as a whole by `send_scheduled_message`
+#[table(name = scheduled_message, public, scheduled(send_scheduled_message))]
+pub struct ScheduledMessage {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+    #[index(btree)]
+    sender: Identity,
+    text: String,
+    created_at: Timestamp,
+}
+
+/// A client can only see their own pending scheduled messages
+#[client_visibility_filter]
+const SCHEDULED_MESSAGE_FILTER: Filter =
+    Filter::Sql("SELECT * FROM scheduled_message WHERE sender = :sender");
+
+/// How far ahead a message may be scheduled
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Room-wide totals, public and unfiltered so every client can show the room's size
+#[table(name = room_stats, public)]
+pub struct RoomStats {
+    #[primary_key]
+    id: u32,
+    online_count: u64,
+    total_users: u64,
+    total_messages: u64,
+}
+
+const ROOM_STATS_ID: u32 = 0;
+
+/// Runs `sweep_presence` periodically to catch clients whose disconnect was never reported
+#[table(name = presence_sweep_schedule, scheduled(sweep_presence))]
+pub struct PresenceSweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// Runs `finish_disconnect` once a disconnected user's grace period is over
+#[table(name = offline_grace_schedule, scheduled(finish_disconnect))]
+pub struct OfflineGraceSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+    identity: Identity,
+    disconnected_at: Timestamp,
+}
+
+/// The top senders by lifetime message count, rebuilt periodically by `rebuild_leaderboard`
+#[table(name = leaderboard, public)]
+pub struct Leaderboard {
+    #[primary_key]
+    /// 1 for the most active user
+    rank: u32,
+    identity: Identity,
+    count: u64,
+}
+
+/// Runs `rebuild_leaderboard` periodically
+#[table(name = leaderboard_schedule, scheduled(rebuild_leaderboard))]
+pub struct LeaderboardSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// A question posted to the room as a whole for users to vote on
+#[table(name = poll, public)]
+pub struct Poll {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    creator: Identity,
+    question: String,
+    created_at: Timestamp,
+    closed: bool,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// One of a poll's answers, numbered from 0 in the order they were given
+#[table(name = poll_option, public)]
+pub struct PollOption {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    poll_id: u64,
+    option_index: u32,
+    text: String,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// A user's current choice in a poll
+#[table(name = poll_vote, public)]
+pub struct PollVote {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    poll_id: u64,
+    voter: Identity,
+    option_index: u32,
+    dummy_join: bool, // workaround join restriction
+}
+
+/// Only authorized clients can see polls
+#[client_visibility_filter]
+const POLL_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT p.*
+    FROM poll p
+    JOIN user u ON u.dummy_join = p.dummy_join
+    WHERE u.authorized = true AND u.identity = :sender
+"#,
+);
+
+/// Only authorized clients can see poll options
+#[client_visibility_filter]
+const POLL_OPTION_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT o.*
+    FROM poll_option o
+    JOIN user u ON u.dummy_join = o.dummy_join
+    WHERE u.authorized = true AND u.identity = :sender
+"#,
+);
+
+/// Only authorized clients can see poll votes
+#[client_visibility_filter]
+const POLL_VOTE_FILTER: Filter = Filter::Sql(
+    r#"
+    SELECT v.*
+    FROM poll_vote v
+    JOIN user u ON u.dummy_join = v.dummy_join
+    WHERE u.authorized = true AND u.identity = :sender
+"#,
+);
+
+const MIN_POLL_OPTIONS: usize = 2;
+const MAX_POLL_OPTIONS: usize = 10;
+
+/// Maximum poll option length, in grapheme clusters
+const MAX_POLL_OPTION_LEN: usize = 100;
+
+/// How often `rebuild_leaderboard` runs
+const LEADERBOARD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Number of users shown on the leaderboard
+const LEADERBOARD_SIZE: usize = 10;
+
+/// How often `sweep_presence` runs
+const PRESENCE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a user stays online without a heartbeat
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+const MICROS_PER_DAY: i64 = 24 * 60 * 60 * 1_000_000;
+
+/// How long after midnight the daily rollup runs, so late messages still land in the right day
+const ROLLUP_DELAY_MICROS: i64 = 5 * 60 * 1_000_000;
+
+/// Number of days of `daily_stats` to keep
+const DAILY_STATS_RETENTION: usize = 365;
+
+/// Maximum number of matches written by a single search
+const MAX_SEARCH_RESULTS: usize = 50;
+
+/// Maximum number of messages written by a single `load_page` call
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[spacetimedb::reducer(init)]
+/// Called when the module is initially published
+pub fn init(ctx: &ReducerContext) {
+    let general = ctx.db.channel().insert(Channel {
+        id: 0,
+        name: "general".to_string(),
+        creator: ctx.identity(),
+        created_at: ctx.timestamp,
+        topic: None,
+        invite_only: false,
+        archived: false,
+        category_id: None,
+        position: 0,
+        next_seq: 1,
+    });
+    ctx.db.config().insert(Config {
+        default_channel_id: general.id,
+        publisher: ctx.sender,
+        ..Config::default()
+    });
+    for emoji in DEFAULT_ALLOWED_REACTIONS {
+        ctx.db.allowed_reaction().insert(AllowedReaction {
+            emoji: emoji.to_string(),
+        });
+    }
+    for (code, emoji) in DEFAULT_SHORTCODES {
+        ctx.db.shortcode().insert(Shortcode {
+            code: code.to_string(),
+            emoji: emoji.to_string(),
+        });
+    }
+    schedule_daily_rollup(ctx);
+    ctx.db.typing_sweep_schedule().insert(TypingSweepSchedule {
+        scheduled_id: 0,
+        scheduled_at: TYPING_SWEEP_INTERVAL.into(),
+    });
+    ctx.db
+        .send_key_sweep_schedule()
+        .insert(SendKeySweepSchedule {
+            scheduled_id: 0,
+            scheduled_at: SEND_KEY_SWEEP_INTERVAL.into(),
+        });
+    ctx.db
+        .name_check_sweep_schedule()
+        .insert(NameCheckSweepSchedule {
+            scheduled_id: 0,
+            scheduled_at: NAME_CHECK_SWEEP_INTERVAL.into(),
+        });
+    ctx.db
+        .presence_sweep_schedule()
+        .insert(PresenceSweepSchedule {
+            scheduled_id: 0,
+            scheduled_at: PRESENCE_SWEEP_INTERVAL.into(),
+        });
+    ctx.db.leaderboard_schedule().insert(LeaderboardSchedule {
+        scheduled_id: 0,
+        scheduled_at: LEADERBOARD_INTERVAL.into(),
+    });
+    ctx.db.prune_users_schedule().insert(PruneUsersSchedule {
+        scheduled_id: 0,
+        scheduled_at: PRUNE_USERS_INTERVAL.into(),
+    });
+    ctx.db.session_trim_schedule().insert(SessionTrimSchedule {
+        scheduled_id: 0,
+        scheduled_at: SESSION_TRIM_INTERVAL.into(),
+    });
+    ctx.db
+        .message_expiry_schedule()
+        .insert(MessageExpirySchedule {
+            scheduled_id: 0,
+            scheduled_at: MESSAGE_EXPIRY_INTERVAL.into(),
+        });
+    ctx.db
+        .notification_prune_schedule()
+        .insert(NotificationPruneSchedule {
+            scheduled_id: 0,
+            scheduled_at: NOTIFICATION_PRUNE_INTERVAL.into(),
+        });
+    ctx.db
+        .channel_invite_sweep_schedule()
+        .insert(ChannelInviteSweepSchedule {
+            scheduled_id: 0,
+            scheduled_at: CHANNEL_INVITE_SWEEP_INTERVAL.into(),
+        });
+
+    // Granted to the first admins by `setup_admins`
+    ctx.db.badge().insert(Badge {
+        id: 0,
+        name: "Founder".to_string(),
+        icon: "🏛️".to_string(),
+        description: "Was here from the very beginning".to_string(),
+    });
+
+    ctx.db.room_stats().insert(RoomStats {
+        id: ROOM_STATS_ID,
+        online_count: ctx.db.user().iter().filter(|user| user.online).count() as u64,
+        total_users: ctx.db.user().count(),
+        total_messages: ctx.db.message().count(),
+    });
+}
+
+/// Applies changes to the `room_stats` totals, clamping at zero so a stray event can't
+/// underflow them.
+fn adjust_room_stats(ctx: &ReducerContext, online: i64, users: i64, messages: i64) {
+    let Some(stats) = ctx.db.room_stats().id().find(ROOM_STATS_ID) else {
+        log::warn!("room_stats row is missing");
+        return;
+    };
+    let apply = |count: u64, delta: i64, what: &str| {
+        let (count, clamped) = apply_count_delta(count, delta);
+        if clamped {
+            log::warn!("room_stats {what} would go negative; clamping to 0");
+        }
+        count
+    };
+    ctx.db.room_stats().id().update(RoomStats {
+        online_count: apply(stats.online_count, online, "online_count"),
+        total_users: apply(stats.total_users, users, "total_users"),
+        total_messages: apply(stats.total_messages, messages, "total_messages"),
+        ..stats
+    });
+}
+
+/// Adds `delta` to a count, clamping at 0. Also returns whether it had to clamp.
+fn apply_count_delta(count: u64, delta: i64) -> (u64, bool) {
+    match count.checked_add_signed(delta) {
+        Some(count) => (count, false),
+        None => (0, true),
+    }
+}
+
+/// How a user going from `was_online` to `online` changes the online count.
+/// A reconnect while already online leaves it unchanged.
+fn online_delta(was_online: bool, online: bool) -> i64 {
+    online as i64 - was_online as i64
+}
+
+#[reducer]
+/// Clients invoke this reducer to pick their unique username, which can't be changed afterwards.
+pub fn set_username(ctx: &ReducerContext, username: String) -> Result<(), String> {
+    let user = validate_name_setter(ctx)?;
+
+    let (username, normalized) = validate_username(ctx, &user, username)?;
+    check_profile_cooldown(user.name_changed_at, ctx.timestamp)?;
+    ctx.db.user().identity().update(User {
+        username: Some(username),
+        username_normalized: Some(normalized),
+        name_changed_at: Some(ctx.timestamp),
+        ..user
+    });
+    Ok(())
+}
+
+/// Checks that the user may pick the given username,
+/// returning it along with its normalized form.
+fn validate_username(
+    ctx: &ReducerContext,
+    user: &User,
+    username: String,
+) -> Result<(String, String), String> {
+    if user.username.is_some() {
+        return Err("Username is already set and cannot be changed".to_string());
+    }
+    let username = validate_name(ctx, username)?;
+    let normalized = normalize_name(&username);
+    let taken = ctx.db.user().iter().any(|other| {
+        other.identity != user.identity && other.username_normalized.as_ref() == Some(&normalized)
+    });
+    if taken {
+        return Err("Name already taken".to_string());
+    }
+    Ok((username, normalized))
+}
+
+#[reducer]
+/// Clients invoke this reducer to find out whether `set_username` would accept a name,
+/// without setting it. The outcome replaces the caller's row in `name_check_result`.
+pub fn check_name(ctx: &ReducerContext, name: String) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let error = validate_username(ctx, &user, name.clone()).err();
+    let expires_at = ctx
+        .timestamp
+        .checked_add_duration(NAME_CHECK_TTL)
+        .unwrap_or(ctx.timestamp);
+    ctx.db
+        .name_check_result()
+        .owner()
+        .insert_or_update(NameCheckResult {
+            owner: ctx.sender,
+            name,
+            available: error.is_none(),
+            error,
+            expires_at,
+        });
+    Ok(())
+}
+
+#[reducer]
+/// Deletes name check results that have outlived `NAME_CHECK_TTL`.
+pub fn expire_name_checks(
+    ctx: &ReducerContext,
+    _schedule: NameCheckSweepSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `expire_name_checks` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let expired: Vec<Identity> = ctx
+        .db
+        .name_check_result()
+        .iter()
+        .filter(|result| result.expires_at <= ctx.timestamp)
+        .map(|result| result.owner)
+        .collect();
+    for owner in expired {
+        ctx.db.name_check_result().owner().delete(owner);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to set the nickname shown in place of their username.
+pub fn set_nickname(ctx: &ReducerContext, nickname: String) -> Result<(), String> {
+    let user = validate_name_setter(ctx)?;
+
+    let nickname = validate_name(ctx, nickname)?;
+    check_profile_cooldown(user.name_changed_at, ctx.timestamp)?;
+    ctx.db.user().identity().update(User {
+        nickname: Some(nickname),
+        name_changed_at: Some(ctx.timestamp),
+        ..user
+    });
+    Ok(())
+}
+
+/// How long users must wait between name changes, and between color changes
+const PROFILE_CHANGE_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Fails if a profile change made at `last_changed` is still within `PROFILE_CHANGE_COOLDOWN`.
+fn check_profile_cooldown(last_changed: Option<Timestamp>, now: Timestamp) -> Result<(), String> {
+    let cooling_down = last_changed
+        .and_then(|last_changed| now.duration_since(last_changed))
+        .is_some_and(|elapsed| elapsed < PROFILE_CHANGE_COOLDOWN);
+    if cooling_down {
+        Err(format!(
+            "Please wait {} seconds between profile changes",
+            PROFILE_CHANGE_COOLDOWN.as_secs()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Builds a `User-<short hex>` nickname for a new user, adding a number if another user
+/// already goes by it.
+fn generate_nickname(ctx: &ReducerContext, identity: Identity) -> String {
+    free_nickname(identity, |candidate| {
+        let normalized = normalize_name(candidate);
+        ctx.db.user().iter().any(|user| {
+            user.username_normalized.as_ref() == Some(&normalized)
+                || user.nickname.as_deref().map(normalize_name) == Some(normalized.clone())
+        })
+    })
+}
+
+/// The first `User-<short hex>` nickname for `identity`, then `User-<short hex>-2` and so
+/// on, for which `taken` is false.
+fn free_nickname(identity: Identity, taken: impl Fn(&str) -> bool) -> String {
+    let base = format!("User-{}", &identity.to_hex().to_string()[..6]);
+    if !taken(&base) {
+        return base;
+    }
+    let mut n: u64 = 2;
+    loop {
+        let candidate = format!("{base}-{n}");
+        if !taken(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// The IANA timezones users may pick from
+const KNOWN_TIMEZONES: &[&str] = &[
+    "UTC",
+    "Africa/Cairo",
+    "Africa/Johannesburg",
+    "Africa/Lagos",
+    "Africa/Nairobi",
+    "America/Anchorage",
+    "America/Argentina/Buenos_Aires",
+    "America/Bogota",
+    "America/Chicago",
+    "America/Denver",
+    "America/Halifax",
+    "America/Los_Angeles",
+    "America/Mexico_City",
+    "America/New_York",
+    "America/Phoenix",
+    "America/Sao_Paulo",
+    "America/Toronto",
+    "Asia/Bangkok",
+    "Asia/Dubai",
+    "Asia/Hong_Kong",
+    "Asia/Jakarta",
+    "Asia/Jerusalem",
+    "Asia/Kolkata",
+    "Asia/Manila",
+    "Asia/Seoul",
+    "Asia/Shanghai",
+    "Asia/Singapore",
+    "Asia/Tokyo",
+    "Atlantic/Reykjavik",
+    "Australia/Adelaide",
+    "Australia/Brisbane",
+    "Australia/Perth",
+    "Australia/Sydney",
+    "Europe/Amsterdam",
+    "Europe/Athens",
+    "Europe/Berlin",
+    "Europe/Dublin",
+    "Europe/Istanbul",
+    "Europe/Lisbon",
+    "Europe/London",
+    "Europe/Madrid",
+    "Europe/Moscow",
+    "Europe/Paris",
+    "Europe/Rome",
+    "Europe/Stockholm",
+    "Europe/Warsaw",
+    "Pacific/Auckland",
+    "Pacific/Honolulu",
+];
+
+#[reducer]
+/// Clients invoke this reducer to set their timezone and locale; empty strings clear them.
+pub fn set_preferences(
+    ctx: &ReducerContext,
+    timezone: String,
+    locale: String,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let timezone = match timezone.trim() {
+        "" => None,
+        timezone if KNOWN_TIMEZONES.contains(&timezone) => Some(timezone.to_string()),
+        _ => return Err("Unknown timezone".to_string()),
+    };
+    let locale = match locale.trim() {
+        "" => None,
+        locale if is_valid_locale(locale) => Some(locale.to_string()),
+        _ => return Err("Invalid locale".to_string()),
+    };
+
+    ctx.db.user().identity().update(User {
+        timezone,
+        locale,
+        ..user
+    });
+    Ok(())
+}
+
+/// Maximum avatar URL length, in bytes
+const MAX_AVATAR_URL_LEN: usize = 2048;
+
+#[reducer]
+/// Clients invoke this reducer to set their avatar to an http(s) URL; an empty string clears it.
+pub fn set_avatar(ctx: &ReducerContext, url: String) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let avatar_url = validate_avatar_url(&url)?;
+    ctx.db.user().identity().update(User { avatar_url, ..user });
+    Ok(())
+}
+
+/// Checks an avatar URL, returning `None` for an empty one, which clears the avatar.
+fn validate_avatar_url(url: &str) -> Result<Option<String>, String> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+    let lower = url.to_ascii_lowercase();
+    if !(lower.starts_with("http://") || lower.starts_with("https://"))
+        || url.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err("Avatars must be http(s) URLs".to_string());
+    }
+    if url.len() > MAX_AVATAR_URL_LEN {
+        return Err(format!(
+            "Avatar URLs must be at most {MAX_AVATAR_URL_LEN} characters"
+        ));
+    }
+    Ok(Some(url.to_string()))
+}
+
+/// Accepts simple language tags: a 2-3 letter language, optionally followed by
+/// a 2 letter or 3 digit region, e.g. `en`, `pt-BR`, `es-419`.
+fn is_valid_locale(locale: &str) -> bool {
+    let mut parts = locale.split('-');
+    let language_ok = parts.next().is_some_and(|language| {
+        (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_lowercase())
+    });
+    let region_ok = parts.next().is_none_or(|region| {
+        (region.len() == 2 && region.chars().all(|c| c.is_ascii_uppercase()))
+            || (region.len() == 3 && region.chars().all(|c| c.is_ascii_digit()))
+    });
+    language_ok && region_ok && parts.next().is_none()
+}
+
+/// Picks a stable color for a user from their identity's bytes.
+fn default_color(identity: Identity) -> u32 {
+    let [r, g, b, ..] = identity.to_byte_array();
+    let color = u32::from_be_bytes([0, r, g, b]);
+    match color {
+        0x000000 | 0xFFFFFF => 0x808080,
+        color => color,
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to set their display color, as 0xRRGGBB.
+pub fn set_color(ctx: &ReducerContext, color: u32) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    if color > 0xFFFFFF {
+        return Err("Colors must be 24-bit 0xRRGGBB values".to_string());
+    }
+    if color == 0x000000 || color == 0xFFFFFF {
+        return Err("Pure black and white are not allowed".to_string());
+    }
+    check_profile_cooldown(user.color_changed_at, ctx.timestamp)?;
+
+    ctx.db.user().identity().update(User {
+        color: Some(color),
+        color_changed_at: Some(ctx.timestamp),
+        ..user
+    });
+    Ok(())
+}
+
+fn validate_identity(ctx: &ReducerContext) -> Result<User, String> {
+    match ctx.db.user().identity().find(ctx.sender) {
+        Some(user) if user.authorized => Ok(user),
+        Some(_) => Err("Unauthorized user attempted to perform an action".to_string()),
+        None => Err("Validation failed: Unknown user".to_string()),
+    }
+}
+
+/// Like `validate_identity`, but also lets guests through when guest read access is enabled.
+fn validate_reader(ctx: &ReducerContext) -> Result<User, String> {
+    match ctx.db.user().identity().find(ctx.sender) {
+        Some(user) if user.authorized || config(ctx).guest_read_access => Ok(user),
+        Some(_) => Err("Unauthorized user attempted to perform an action".to_string()),
+        None => Err("Validation failed: Unknown user".to_string()),
+    }
+}
+
+fn validate_admin(ctx: &ReducerContext) -> Result<User, String> {
+    require_admin(validate_identity(ctx)?)
+}
+
+fn require_admin(user: User) -> Result<User, String> {
+    if user.admin {
+        Ok(user)
+    } else {
+        Err("Only admins may perform this action".to_string())
+    }
+}
+
+fn is_moderator(user: &User) -> bool {
+    user.moderator || user.admin
+}
+
+/// Returns the current server settings, falling back to the defaults if the row is missing.
+fn config(ctx: &ReducerContext) -> Config {
+    ctx.db.config().id().find(CONFIG_ID).unwrap_or_default()
+}
+
+/// Returns the UTC day a timestamp falls on, as days since the Unix epoch.
+fn day_index(timestamp: Timestamp) -> u64 {
+    timestamp
+        .to_micros_since_unix_epoch()
+        .div_euclid(MICROS_PER_DAY)
+        .max(0) as u64
+}
+
+/// Counts user-perceived characters, so e.g. a ZWJ family emoji counts as one.
+fn grapheme_len(text: &str) -> usize {
+    text.graphemes(true).count()
+}
+
+/// Folds a display name into the form used for uniqueness checks,
+/// so that e.g. "Alice", "alice" and "ＡＬＩＣＥ" all collide.
+fn normalize_name(name: &str) -> String {
+    name.nfkc().collect::<String>().to_lowercase()
+}
+
+/// Letters, digits, underscores, hyphens and spaces are allowed in names.
+fn is_allowed_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | ' ')
+}
+
+/// Names shorter than this, in graphemes, are easily confused with one another.
+const MIN_NAME_LEN: usize = 2;
+
+/// Takes a name and checks if it's acceptable as a user's name.
+fn validate_name(ctx: &ReducerContext, name: String) -> Result<String, String> {
+    validate_name_within(name, config(ctx).max_name_len as usize)
+}
+
+/// `validate_name` with names of up to `max_len` grapheme clusters allowed.
+fn validate_name_within(name: String, max_len: usize) -> Result<String, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        Err("Names must not be empty".to_string())
+    } else if grapheme_len(&name) < MIN_NAME_LEN {
+        Err("Name too short".to_string())
+    } else if grapheme_len(&name) > max_len {
+        Err(format!("Names must be at most {max_len} characters"))
+    } else if !normalize_name(&name).chars().all(is_allowed_name_char) {
+        Err("Name contains invalid characters".to_string())
+    } else {
+        Ok(name)
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to send messages. Messages starting with `/` are slash commands;
+/// a leading `//` posts a literal `/`.
+pub fn send_message(ctx: &ReducerContext, text: String) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let (kind, text) = match parse_slash_command(&text)? {
+        SlashCommand::Post(kind, text) => (kind, text),
+        SlashCommand::Nick(username) => return set_username(ctx, username),
+    };
+    post_message(ctx, user, GLOBAL_CHANNEL_ID, kind, text)?;
+    Ok(())
+}
+
+/// What `send_message` should do with a message
+#[derive(Debug, PartialEq)]
+enum SlashCommand {
+    Post(MessageKind, String),
+    /// `/nick`, which picks the sender's username like `set_username` instead of posting anything
+    Nick(String),
+}
+
+const SHRUG: &str = r"¯\_(ツ)_/¯";
+
+fn parse_slash_command(text: &str) -> Result<SlashCommand, String> {
+    let Some(command) = text.strip_prefix('/') else {
+        return Ok(SlashCommand::Post(MessageKind::Normal, text.to_string()));
+    };
+    if command.starts_with('/') {
+        return Ok(SlashCommand::Post(MessageKind::Normal, command.to_string()));
+    }
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let args = args.trim().to_string();
+    match name {
+        "me" => Ok(SlashCommand::Post(MessageKind::Action, args)),
+        "shrug" if args.is_empty() => {
+            Ok(SlashCommand::Post(MessageKind::Normal, SHRUG.to_string()))
+        }
+        "shrug" => Ok(SlashCommand::Post(
+            MessageKind::Normal,
+            format!("{args} {SHRUG}"),
+        )),
+        "nick" => Ok(SlashCommand::Nick(args)),
+        _ => Err("Unknown command".to_string()),
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to send a message they may retry, e.g. on a flaky network.
+/// A repeat of a `key` the caller used in the last 10 minutes is ignored.
+pub fn send_message_idempotent(
+    ctx: &ReducerContext,
+    text: String,
+    key: String,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    validate_idempotency_key(&key)?;
+    let recent: Vec<RecentSendKey> = ctx
+        .db
+        .recent_send_key()
+        .sender()
+        .filter(ctx.sender)
+        .collect();
+    if is_replay(&recent, &key, ctx.timestamp) {
+        return Ok(());
+    }
+
+    if let Some(message) = post_message(ctx, user, GLOBAL_CHANNEL_ID, MessageKind::Normal, text)? {
+        ctx.db.recent_send_key().insert(RecentSendKey {
+            id: 0,
+            sender: ctx.sender,
+            key,
+            message_id: message.id,
+            created_at: ctx.timestamp,
+        });
+    }
+    Ok(())
+}
+
+fn validate_idempotency_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("Idempotency keys must not be empty".to_string());
+    }
+    if key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return Err(format!(
+            "Idempotency keys must be at most {MAX_IDEMPOTENCY_KEY_LEN} bytes"
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the sender already used `key` among their `recent` send keys within the window.
+fn is_replay(recent: &[RecentSendKey], key: &str, now: Timestamp) -> bool {
+    recent
+        .iter()
+        .any(|recent| recent.key == key && !send_key_expired(recent, now))
+}
+
+fn send_key_expired(recent: &RecentSendKey, now: Timestamp) -> bool {
+    now.duration_since(recent.created_at)
+        .is_some_and(|elapsed| elapsed >= SEND_KEY_WINDOW)
+}
+
+#[reducer]
+/// Deletes send keys older than `SEND_KEY_WINDOW`.
+pub fn prune_send_keys(
+    ctx: &ReducerContext,
+    _schedule: SendKeySweepSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Reducer `prune_send_keys` may only be invoked by the scheduler".to_string());
+    }
+
+    let expired: Vec<u64> = ctx
+        .db
+        .recent_send_key()
+        .iter()
+        .filter(|recent| send_key_expired(recent, ctx.timestamp))
+        .map(|recent| recent.id)
+        .collect();
+    for id in expired {
+        ctx.db.recent_send_key().id().delete(id);
+    }
+    Ok(())
+}
+
+/// Whether the user's messages must be approved under `moderate_first_message`:
+/// only until they've had one approved, and never for users who've already posted.
+fn needs_approval(ctx: &ReducerContext, user: &User) -> bool {
+    config(ctx).moderate_first_message
+        && !user.trusted
+        && !is_moderator(user)
+        && ctx
+            .db
+            .message()
+            .sender()
+            .filter(user.identity)
+            .next()
+            .is_none()
+}
+
+/// Fails if the user's messages must be approved. Only plain room messages are held for
+/// approval, so other kinds of sends are turned down instead.
+fn check_no_approval_needed(ctx: &ReducerContext, user: &User) -> Result<(), String> {
+    if needs_approval(ctx, user) {
+        Err(
+            "Your first message must be a plain room message, for a moderator to approve"
+                .to_string(),
+        )
+    } else {
+        Ok(())
+    }
+}
+
+/// Queues an already validated message in `pending_message` for a moderator to approve.
+fn hold_for_approval(
+    ctx: &ReducerContext,
+    sender: Identity,
+    channel_id: u64,
+    kind: MessageKind,
+    text: String,
+) -> Result<(), String> {
+    let awaiting = ctx
+        .db
+        .pending_message()
+        .sender()
+        .filter(sender)
+        .any(|pending| pending.status == PendingStatus::Pending);
+    if awaiting {
+        return Err("Your first message is awaiting approval".to_string());
+    }
+
+    ctx.db.pending_message().insert(PendingMessage {
+        id: 0,
+        sender,
+        channel_id,
+        sent: ctx.timestamp,
+        text,
+        kind,
+        status: PendingStatus::Pending,
+        reject_reason: None,
+        dummy_join: true,
+    });
+    Ok(())
+}
+
+/// Returns a pending message awaiting approval, if the caller is a moderator.
+fn find_pending_for_review(
+    ctx: &ReducerContext,
+    pending_id: u64,
+) -> Result<PendingMessage, String> {
+    let user = validate_identity(ctx)?;
+    if !is_moderator(&user) {
+        return Err("Only moderators may review pending messages".to_string());
+    }
+    match ctx.db.pending_message().id().find(pending_id) {
+        Some(pending) if pending.status == PendingStatus::Pending => Ok(pending),
+        Some(_) => Err("This message has already been reviewed".to_string()),
+        None => Err("Unknown pending message".to_string()),
+    }
+}
+
+#[reducer]
+/// Moderators invoke this reducer to post a pending message as originally sent,
+/// after which its author's messages are posted directly.
+pub fn approve_pending(ctx: &ReducerContext, pending_id: u64) -> Result<(), String> {
+    let pending = find_pending_for_review(ctx, pending_id)?;
+    let author = ctx
+        .db
+        .user()
+        .identity()
+        .find(pending.sender)
+        .ok_or("Unknown user")?;
+    if pending.channel_id != GLOBAL_CHANNEL_ID {
+        let channel = ctx
+            .db
+            .channel()
+            .id()
+            .find(pending.channel_id)
+            .ok_or("The channel this message was sent to no longer exists")?;
+        validate_not_archived(&channel)?;
+    }
+
+    ctx.db.pending_message().id().delete(pending.id);
+    let mentions = resolve_mentions(ctx, &pending.text);
+    insert_user_message(
+        ctx,
+        author,
+        pending.channel_id,
+        pending.kind,
+        pending.text,
+        pending.sent,
+        &mentions,
+    );
+    if let Some(author) = ctx.db.user().identity().find(pending.sender) {
+        ctx.db.user().identity().update(User {
+            trusted: true,
+            ..author
+        });
+    }
+    audit(ctx, "approve_pending", Some(pending.sender), None);
+    Ok(())
+}
+
+#[reducer]
+/// Moderators invoke this reducer to turn down a pending message, telling its author why.
+pub fn reject_pending(ctx: &ReducerContext, pending_id: u64, reason: String) -> Result<(), String> {
+    let pending = find_pending_for_review(ctx, pending_id)?;
+
+    let reason = reason.trim().to_string();
+    if grapheme_len(&reason) > MAX_REPORT_REASON_LEN {
+        return Err(format!(
+            "Reasons must be at most {MAX_REPORT_REASON_LEN} characters"
+        ));
+    }
+    audit(ctx, "reject_pending", Some(pending.sender), None);
+    ctx.db.pending_message().id().update(PendingMessage {
+        status: PendingStatus::Rejected,
+        reject_reason: (!reason.is_empty()).then_some(reason),
+        ..pending
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to post a message to the room at a later time, up to 30 days ahead.
+pub fn schedule_message(
+    ctx: &ReducerContext,
+    text: String,
+    send_at: Timestamp,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let text = validate_message(ctx, &user, text)?;
+    if resolve_mentions(ctx, &text).len() > config(ctx).max_mentions as usize {
+        return Err("Too many mentions".to_string());
+    }
+    match send_at.duration_since(ctx.timestamp) {
+        None => return Err("Scheduled messages must be sent in the future".to_string()),
+        Some(ahead) if ahead > MAX_SCHEDULE_AHEAD => {
+            return Err("Messages can be scheduled at most 30 days ahead".to_string());
+        }
+        Some(_) => {}
+    }
+
+    ctx.db.scheduled_message().insert(ScheduledMessage {
+        scheduled_id: 0,
+        scheduled_at: send_at.into(),
+        sender: ctx.sender,
+        text,
+        created_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to cancel one of their pending scheduled messages.
+pub fn cancel_scheduled_message(ctx: &ReducerContext, scheduled_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    match ctx.db.scheduled_message().scheduled_id().find(scheduled_id) {
+        Some(scheduled) if scheduled.sender == ctx.sender => {
+            ctx.db
+                .scheduled_message()
+                .scheduled_id()
+                .delete(scheduled_id);
+            Ok(())
+        }
+        _ => Err("Unknown scheduled message".to_string()),
+    }
+}
+
+#[reducer]
+/// Posts a scheduled message on behalf of its author, unless they've lost authorization since.
+pub fn send_scheduled_message(
+    ctx: &ReducerContext,
+    scheduled: ScheduledMessage,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `send_scheduled_message` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let user = match ctx.db.user().identity().find(scheduled.sender) {
+        Some(user) if user.authorized => user,
+        _ => {
+            log::info!(
+                "Dropped scheduled message {} from unauthorized user {:?}",
+                scheduled.scheduled_id,
+                scheduled.sender.to_hex()
+            );
+            return Ok(());
+        }
+    };
+    // The author might have been muted or rate limited since scheduling the message,
+    // so it goes through the same checks as one they sent now. Nobody is waiting on the
+    // call, so a refusal is reported through `send_rejection` instead.
+    if let Err(err) = post_message(
+        ctx,
+        user,
+        GLOBAL_CHANNEL_ID,
+        MessageKind::Normal,
+        scheduled.text,
+    ) {
+        log::info!(
+            "Dropped scheduled message {} from {:?}: {err}",
+            scheduled.scheduled_id,
+            scheduled.sender.to_hex()
+        );
+        record_send_rejection(ctx, scheduled.sender, &err);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to send a message that deletes itself after `ttl_secs` seconds,
+/// which must be between 10 seconds and 24 hours.
+pub fn send_ephemeral_message(
+    ctx: &ReducerContext,
+    text: String,
+    ttl_secs: u64,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    check_no_approval_needed(ctx, &user)?;
+
+    if !(MIN_MESSAGE_TTL_SECS..=MAX_MESSAGE_TTL_SECS).contains(&ttl_secs) {
+        return Err(format!(
+            "Message lifetimes must be between {MIN_MESSAGE_TTL_SECS} and {MAX_MESSAGE_TTL_SECS} seconds"
+        ));
+    }
+    let expires_at = ctx
+        .timestamp
+        .checked_add_duration(Duration::from_secs(ttl_secs))
+        .ok_or("Invalid message lifetime")?;
+
+    let Some(message) = post_message(ctx, user, GLOBAL_CHANNEL_ID, MessageKind::Normal, text)?
+    else {
+        return Ok(());
+    };
+    ctx.db.message().id().update(Message {
+        expires_at: Some(expires_at),
+        ..message
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Deletes ephemeral messages whose lifetime has run out.
+pub fn expire_messages(
+    ctx: &ReducerContext,
+    _schedule: MessageExpirySchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Reducer `expire_messages` may only be invoked by the scheduler".to_string());
+    }
+
+    let expired: Vec<Message> = ctx
+        .db
+        .message()
+        .iter()
+        .filter(|message| is_expired(message.expires_at, ctx.timestamp))
+        .collect();
+    for message in expired {
+        delete_message_cascade(ctx, message.id);
+        ctx.db.expired_message().insert(ExpiredMessage {
+            message_id: message.id,
+            channel_id: message.channel_id,
+            expired_at: ctx.timestamp,
+        });
+    }
+    Ok(())
+}
+
+/// Whether a message with the given expiry time has run out by `now`.
+fn is_expired(expires_at: Option<Timestamp>, now: Timestamp) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+#[reducer]
+/// Clients invoke this reducer to repost a message they can see to the room as a whole.
+pub fn forward_message(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    check_no_approval_needed(ctx, &user)?;
+
+    let original = find_readable_message(ctx, &user, message_id)?;
+    if original.sender == DELETED_USER {
+        return Err("Cannot forward a deleted user's message".to_string());
+    }
+    let (forwarded_from, forwarded_message_id) =
+        match (original.forwarded_from, original.forwarded_message_id) {
+            (Some(author), Some(first_id)) => (author, first_id),
+            _ => (original.sender, original.id),
+        };
+
+    let Some(message) = post_message(
+        ctx,
+        user,
+        GLOBAL_CHANNEL_ID,
+        MessageKind::Normal,
+        original.text,
+    )?
+    else {
+        return Ok(());
+    };
+    ctx.db.message().id().update(Message {
+        forwarded_from: Some(forwarded_from),
+        forwarded_message_id: Some(forwarded_message_id),
+        ..message
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to send a message with files they've uploaded elsewhere.
+pub fn send_message_with_attachments(
+    ctx: &ReducerContext,
+    text: String,
+    attachments: Vec<AttachmentInput>,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    check_no_approval_needed(ctx, &user)?;
+
+    if attachments.len() > MAX_ATTACHMENTS {
+        return Err(format!(
+            "Messages may have at most {MAX_ATTACHMENTS} attachments"
+        ));
+    }
+    let max_bytes = config(ctx).max_attachment_bytes;
+    for attachment in &attachments {
+        validate_attachment(attachment, max_bytes)?;
+    }
+
+    let Some(message) = post_message(ctx, user, GLOBAL_CHANNEL_ID, MessageKind::Normal, text)?
+    else {
+        return Ok(());
+    };
+    for attachment in attachments {
+        ctx.db.attachment().insert(Attachment {
+            id: 0,
+            message_id: message.id,
+            url: attachment.url,
+            mime_type: attachment.mime_type,
+            size_bytes: attachment.size_bytes,
+            filename: attachment.filename,
+        });
+    }
+    Ok(())
+}
+
+fn validate_attachment(attachment: &AttachmentInput, max_bytes: u64) -> Result<(), String> {
+    let url = attachment.url.to_ascii_lowercase();
+    if !(url.starts_with("http://") || url.starts_with("https://"))
+        || attachment.url.len() > MAX_ATTACHMENT_URL_LEN
+        || attachment.url.chars().any(char::is_whitespace)
+    {
+        Err("Attachment URLs must be http(s) links".to_string())
+    } else if !ALLOWED_ATTACHMENT_TYPES.contains(&attachment.mime_type.as_str()) {
+        Err(format!(
+            "Attachments of type {} are not allowed",
+            attachment.mime_type
+        ))
+    } else if attachment.size_bytes > max_bytes {
+        Err(format!("Attachments must be at most {max_bytes} bytes"))
+    } else if attachment.filename.trim().is_empty()
+        || grapheme_len(&attachment.filename) > MAX_ATTACHMENT_FILENAME_LEN
+        || has_disallowed_control_chars(&attachment.filename)
+    {
+        Err("Invalid attachment filename".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Validates and stores a message from the user, updating their stats along the way.
+///
+/// Returns `None` if the message was held for approval or dropped as spam. Failing the reducer
+/// would roll back the violation along with everything else, so the reducer succeeds and the
+/// reason is left in the sender's `send_rejection` row instead.
+fn post_message(
+    ctx: &ReducerContext,
+    user: User,
+    channel_id: u64,
+    kind: MessageKind,
+    text: String,
+) -> Result<Option<Message>, String> {
+    let text = validate_message(ctx, &user, expand_shortcodes(ctx, &text))?;
+    let mentions = resolve_mentions(ctx, &text);
+    if mentions.len() > config(ctx).max_mentions as usize {
+        return Err("Too many mentions".to_string());
+    }
+    let sender = user.identity;
+    check_may_send(ctx, &user)?;
+    if needs_approval(ctx, &user) {
+        hold_for_approval(ctx, sender, channel_id, kind, text)?;
+        return Ok(None);
+    }
+    let Some(user) = pass_spam_checks(ctx, user, &text)? else {
+        return Ok(None);
+    };
+
+    let hash = hash_bytes(text.trim());
+    let message = insert_user_message(ctx, user, channel_id, kind, text, ctx.timestamp, &mentions);
+    record_send(ctx, sender, hash);
+    Ok(Some(message))
+}
+
+/// Fails if the user is muted or their account is too new. Moderators are exempt.
+fn check_may_send(ctx: &ReducerContext, user: &User) -> Result<(), String> {
+    if is_moderator(user) {
+        return Ok(());
+    }
+    check_not_muted(ctx, user.identity)?;
+    check_account_age(user, ctx.timestamp, config(ctx).min_account_age_secs)
+}
+
+/// Fails if the user's account is younger than `min_age_secs`.
+fn check_account_age(user: &User, now: Timestamp, min_age_secs: u64) -> Result<(), String> {
+    if account_age_secs(user, now) < min_age_secs {
+        return Err("Account too new to send messages".to_string());
+    }
+    Ok(())
+}
+
+/// Runs the duplicate and byte budget checks, spending the user's budget on the message.
+///
+/// Returns `None` if the message must be dropped as spam, after recording the violation.
+/// Moderators skip the duplicate check, and going over budget is an error for them.
+fn pass_spam_checks(ctx: &ReducerContext, user: User, text: &str) -> Result<Option<User>, String> {
+    let sender = user.identity;
+    let exempt = is_spam_exempt(&user);
+    if !exempt {
+        if let Err(reason) = check_duplicate(ctx, sender, hash_bytes(text.trim())) {
+            record_spam_violation(ctx, sender, &reason);
+            return Ok(None);
+        }
+    }
+    match spend_byte_budget(&config(ctx), user, text.len() as u64, ctx.timestamp) {
+        Ok(user) => Ok(Some(user)),
+        Err(reason) if !exempt => {
+            record_spam_violation(ctx, sender, &reason);
+            Ok(None)
+        }
+        Err(reason) => Err(reason),
+    }
+}
+
+/// Whether a user skips the duplicate check and gets an error rather than a spam drop when
+/// over budget.
+fn is_spam_exempt(user: &User) -> bool {
+    is_moderator(user)
+}
+
+/// Tells the sender why their latest message wasn't sent, replacing any earlier reason.
+fn record_send_rejection(ctx: &ReducerContext, sender: Identity, reason: &str) {
+    ctx.db
+        .send_rejection()
+        .identity()
+        .insert_or_update(SendRejection {
+            identity: sender,
+            reason: reason.to_string(),
+            rejected_at: ctx.timestamp,
+        });
+}
+
+/// Remembers a stored message for the duplicate check and clears any earlier rejection.
+fn record_send(ctx: &ReducerContext, sender: Identity, text_hash: Hash) {
+    ctx.db.send_state().identity().insert_or_update(SendState {
+        identity: sender,
+        last_text_hash: text_hash,
+        last_sent: ctx.timestamp,
+    });
+    ctx.db.send_rejection().identity().delete(sender);
+}
+
+/// Counts a stored message towards the sender's stats, streak and XP.
+fn count_message(ctx: &ReducerContext, user: User, text: &str) {
+    let sender = user.identity;
+    record_user_stats(ctx, sender, grapheme_len(text) as u64);
+    update_streak(ctx, user);
+    award_message_xp(ctx, sender);
+}
+
+/// Stores a message that has passed `post_message`'s checks, counting it towards the
+/// sender's stats, streak and XP.
+fn insert_user_message(
+    ctx: &ReducerContext,
+    user: User,
+    channel_id: u64,
+    kind: MessageKind,
+    text: String,
+    sent: Timestamp,
+    mentions: &[Identity],
+) -> Message {
+    log::info!("{}", text);
+    let message = ctx.db.message().insert(Message {
+        id: 0,
+        channel_id,
+        sender: user.identity,
+        text,
+        sent,
+        edited_at: None,
+        hidden: false,
+        expires_at: None,
+        forwarded_from: None,
+        forwarded_message_id: None,
+        seq: next_seq(ctx, channel_id),
+        kind,
+        dummy_join: true,
+    });
+    adjust_room_stats(ctx, 0, 0, 1);
+    notify_mentions(ctx, &message, mentions, &[]);
+    store_links(ctx, &message);
+    count_message(ctx, user, &message.text);
+    message
+}
+
+/// Fails if the sender is muted.
+fn check_not_muted(ctx: &ReducerContext, sender: Identity) -> Result<(), String> {
+    let mute = ctx.db.mute().identity().find(sender);
+    if is_muted(mute.as_ref(), ctx.timestamp) {
+        Err("You are muted".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether a mute is still in effect at `now`. Ended mutes keep their row.
+fn is_muted(mute: Option<&Mute>, now: Timestamp) -> bool {
+    mute.is_some_and(|mute| mute.until > now)
+}
+
+/// Leaves the reason in the sender's `send_rejection` row and counts it against them,
+/// muting them automatically once they reach
+/// `SPAM_VIOLATION_LIMIT` within `SPAM_VIOLATION_WINDOW`.
+fn record_spam_violation(ctx: &ReducerContext, sender: Identity, reason: &str) {
+    log::info!("Dropped a message from {:?}: {reason}", sender.to_hex());
+    record_send_rejection(ctx, sender, reason);
+    let Some(user) = ctx.db.user().identity().find(sender) else {
+        return;
+    };
+    let in_window = user.violation_window_start.is_some_and(|start| {
+        ctx.timestamp
+            .duration_since(start)
+            .is_some_and(|elapsed| elapsed < SPAM_VIOLATION_WINDOW)
+    });
+    let (violation_window_start, violation_count) = if in_window {
+        (user.violation_window_start, user.violation_count + 1)
+    } else {
+        (Some(ctx.timestamp), 1)
+    };
+    if violation_count < SPAM_VIOLATION_LIMIT {
+        ctx.db.user().identity().update(User {
+            violation_count,
+            violation_window_start,
+            ..user
+        });
+        return;
+    }
+
+    ctx.db.user().identity().update(User {
+        violation_count: 0,
+        violation_window_start: None,
+        ..user
+    });
+    let auto_mutes = ctx
+        .db
+        .mute()
+        .identity()
+        .find(sender)
+        .map_or(0, |mute| mute.auto_mutes);
+    let length = AUTO_MUTE_BASE
+        .checked_mul(1 << auto_mutes.min(16))
+        .map_or(AUTO_MUTE_MAX, |length| length.min(AUTO_MUTE_MAX));
+    let until = ctx
+        .timestamp
+        .checked_add_duration(length)
+        .unwrap_or(ctx.timestamp);
+    ctx.db.mute().identity().insert_or_update(Mute {
+        identity: sender,
+        until,
+        auto_mutes: auto_mutes + 1,
+        dummy_join: true,
+    });
+    audit_as(ctx, ctx.identity(), "auto_mute", Some(sender), None);
+    log::info!(
+        "Muted {:?} for {} seconds",
+        sender.to_hex(),
+        length.as_secs()
+    );
+}
+
+/// Takes the next `seq` from a channel's counter, or 0 for the room as a whole.
+fn next_seq(ctx: &ReducerContext, channel_id: u64) -> u64 {
+    match ctx.db.channel().id().find(channel_id) {
+        Some(channel) => {
+            let (seq, channel) = take_seq(channel);
+            ctx.db.channel().id().update(channel);
+            seq
+        }
+        None => 0,
+    }
+}
+
+/// Splits a channel's next `seq` off its counter, returning it and the advanced channel.
+fn take_seq(channel: Channel) -> (u64, Channel) {
+    let seq = channel.next_seq;
+    (
+        seq,
+        Channel {
+            next_seq: seq + 1,
+            ..channel
+        },
+    )
+}
+
+/// Replaces known `:shortcode:`s with their emoji, leaving unknown ones as they are.
+fn expand_shortcodes(ctx: &ReducerContext, text: &str) -> String {
+    expand_shortcodes_with(text, |code| {
+        ctx.db
+            .shortcode()
+            .code()
+            .find(code.to_string())
+            .map(|shortcode| shortcode.emoji)
+    })
+}
+
+/// `expand_shortcodes`, looking emoji up with `lookup`.
+fn expand_shortcodes_with(text: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .and_then(|end| Some((lookup(&after[..end])?, end)));
+        match emoji {
+            Some((emoji, end)) => {
+                expanded.push_str(&emoji);
+                rest = &after[end + 1..];
+            }
+            None => {
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Resolves the `@name` mentions in a message to the users they name, without duplicates.
+/// Longer names are tried first, so `@alice_b` doesn't resolve to a user named `alice`.
+fn resolve_mentions(ctx: &ReducerContext, text: &str) -> Vec<Identity> {
+    let names = ctx
+        .db
+        .user()
+        .iter()
+        .filter_map(|user| Some((user.username_normalized?, user.identity)));
+    resolve_mentions_among(text, names)
+}
+
+/// `resolve_mentions` against the given normalized usernames.
+fn resolve_mentions_among(
+    text: &str,
+    names: impl IntoIterator<Item = (String, Identity)>,
+) -> Vec<Identity> {
+    let mut names: Vec<(String, Identity)> = names.into_iter().collect();
+    names.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+    let mut mentioned = Vec::new();
+    for (at, _) in text.match_indices('@') {
+        let rest = normalize_name(&text[at + 1..]);
+        let found = names.iter().find(|(name, _)| {
+            rest.starts_with(name.as_str())
+                && !rest[name.len()..]
+                    .starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '-')
+        });
+        if let Some(&(_, identity)) = found {
+            if !mentioned.contains(&identity) {
+                mentioned.push(identity);
+            }
+        }
+    }
+    mentioned
+}
+
+/// Records the mentions in a message and notifies the mentioned users, skipping the sender,
+/// users who can't see the message or opted out of mentions, and (for notifications) anyone
+/// in DND mode.
+///
+/// Users in `already_notified` get a `mention` row but no second notification.
+fn notify_mentions(
+    ctx: &ReducerContext,
+    message: &Message,
+    mentions: &[Identity],
+    already_notified: &[Identity],
+) {
+    for &recipient in mentions {
+        if recipient == message.sender {
+            continue;
+        }
+        let Some(user) = ctx.db.user().identity().find(recipient) else {
+            continue;
+        };
+        let can_read = message.channel_id == GLOBAL_CHANNEL_ID
+            || is_channel_member(ctx, message.channel_id, recipient);
+        let delivery = mention_delivery(&user, can_read, &notification_prefs(ctx, recipient));
+        if delivery == MentionDelivery::Skip {
+            continue;
+        }
+        ctx.db.mention().insert(Mention {
+            id: 0,
+            message_id: message.id,
+            mentioned: recipient,
+            mentioner: message.sender,
+            created_at: ctx.timestamp,
+        });
+        if delivery == MentionDelivery::MentionOnly || already_notified.contains(&recipient) {
+            continue;
+        }
+        ctx.db.notification().insert(Notification {
+            id: 0,
+            recipient,
+            message_id: message.id,
+            channel_id: message.channel_id,
+            sender: message.sender,
+            created: ctx.timestamp,
+            delivered: false,
+        });
+    }
+}
+
+/// What a mention of a user records
+#[derive(Debug, PartialEq)]
+enum MentionDelivery {
+    Skip,
+    /// A `mention` row but no notification, for users in DND mode
+    MentionOnly,
+    Notify,
+}
+
+/// Decides what mentioning `recipient` records, given whether they can read the message.
+fn mention_delivery(
+    recipient: &User,
+    can_read: bool,
+    prefs: &NotificationPrefs,
+) -> MentionDelivery {
+    if !recipient.authorized || !can_read || !prefs.notify_on_mention {
+        MentionDelivery::Skip
+    } else if recipient.dnd {
+        MentionDelivery::MentionOnly
+    } else {
+        MentionDelivery::Notify
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to load their undelivered notifications, newest first,
+/// into `loaded_notification`, replacing the previously loaded ones.
+/// Loaded notifications are marked delivered, so they aren't loaded again.
+pub fn load_my_notifications(ctx: &ReducerContext) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    ctx.db.loaded_notification().owner().delete(ctx.sender);
+
+    let mut pending: Vec<Notification> = ctx
+        .db
+        .notification()
+        .recipient()
+        .filter(ctx.sender)
+        .filter(|notification| !notification.delivered)
+        .collect();
+    pending.sort_by_key(|notification| std::cmp::Reverse(notification.created));
+
+    for notification in pending {
+        ctx.db.loaded_notification().insert(LoadedNotification {
+            id: 0,
+            owner: ctx.sender,
+            notification_id: notification.id,
+            message_id: notification.message_id,
+            channel_id: notification.channel_id,
+            sender: notification.sender,
+            created: notification.created,
+        });
+        ctx.db.notification().id().update(Notification {
+            delivered: true,
+            ..notification
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Deletes delivered notifications older than `DELIVERED_NOTIFICATION_RETENTION`.
+pub fn prune_notifications(
+    ctx: &ReducerContext,
+    _schedule: NotificationPruneSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `prune_notifications` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let stale: Vec<u64> = ctx
+        .db
+        .notification()
+        .iter()
+        .filter(|notification| {
+            notification.delivered
+                && ctx
+                    .timestamp
+                    .duration_since(notification.created)
+                    .is_some_and(|age| age > DELIVERED_NOTIFICATION_RETENTION)
+        })
+        .map(|notification| notification.id)
+        .collect();
+    for id in stale {
+        ctx.db.notification().id().delete(id);
+    }
+    Ok(())
+}
+
+fn notification_prefs(ctx: &ReducerContext, identity: Identity) -> NotificationPrefs {
+    ctx.db
+        .notification_prefs()
+        .identity()
+        .find(identity)
+        .unwrap_or_else(|| NotificationPrefs::defaults(identity))
+}
+
+#[reducer]
+/// Clients invoke this reducer to choose what they're notified about.
+pub fn set_notification_prefs(
+    ctx: &ReducerContext,
+    notify_on_mention: bool,
+    notify_on_dm: bool,
+    notify_on_all_messages: bool,
+    hide_read_receipts: bool,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    ctx.db
+        .notification_prefs()
+        .identity()
+        .insert_or_update(NotificationPrefs {
+            identity: ctx.sender,
+            notify_on_mention,
+            notify_on_dm,
+            notify_on_all_messages,
+            hide_read_receipts,
+        });
+    let markers: Vec<ConversationReadMarker> = ctx
+        .db
+        .conversation_read_marker()
+        .identity()
+        .filter(ctx.sender)
+        .filter(|marker| marker.hidden != hide_read_receipts)
+        .collect();
+    for marker in markers {
+        ctx.db
+            .conversation_read_marker()
+            .id()
+            .update(ConversationReadMarker {
+                hidden: hide_read_receipts,
+                ..marker
+            });
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to turn do-not-disturb mode on or off.
+pub fn set_dnd(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    ctx.db.user().identity().update(User {
+        dnd: enabled,
+        ..user
+    });
+    Ok(())
+}
+
+/// Gives the sender 1 XP for a message, unless they've hit this hour's cap.
+fn award_message_xp(ctx: &ReducerContext, sender: Identity) {
+    let progression = find_progression(ctx, sender);
+    let Some((hour, xp_this_hour)) =
+        next_hourly_xp(&progression, ctx.timestamp, config(ctx).xp_hourly_cap)
+    else {
+        return;
+    };
+    let xp = progression.xp + 1;
+    set_xp(
+        ctx,
+        Progression {
+            hour,
+            xp_this_hour,
+            ..progression
+        },
+        xp,
+    );
+}
+
+/// The hour and XP earned in it after one more message at `now`, or `None` if the user
+/// has already earned `cap` XP this hour. The count starts over each hour.
+fn next_hourly_xp(progression: &Progression, now: Timestamp, cap: u32) -> Option<(u64, u32)> {
+    let hour = (now.to_micros_since_unix_epoch() / MICROS_PER_HOUR) as u64;
+    let xp_this_hour = if progression.hour == hour {
+        progression.xp_this_hour
+    } else {
+        0
+    };
+    (xp_this_hour < cap).then_some((hour, xp_this_hour + 1))
+}
+
+fn find_progression(ctx: &ReducerContext, identity: Identity) -> Progression {
+    ctx.db
+        .progression()
+        .identity()
+        .find(identity)
+        .unwrap_or(Progression {
+            identity,
+            xp: 0,
+            level: 1,
+            hour: 0,
+            xp_this_hour: 0,
+        })
+}
+
+/// Returns the level a user with the given XP is at.
+fn level_for_xp(xp: u64) -> u32 {
+    LEVEL_THRESHOLDS
+        .iter()
+        .filter(|&&threshold| xp >= threshold)
+        .count() as u32
+}
+
+/// Stores a user's new XP total, announcing it in the room if they reached a higher level.
+fn set_xp(ctx: &ReducerContext, progression: Progression, xp: u64) {
+    let level = level_for_xp(xp);
+    if level > progression.level {
+        if let Some(user) = ctx.db.user().identity().find(progression.identity) {
+            post_system_message(
+                ctx,
+                GLOBAL_CHANNEL_ID,
+                format!("{} reached level {level}!", display_name(&user)),
+            );
+        }
+    }
+    ctx.db
+        .progression()
+        .identity()
+        .insert_or_update(Progression {
+            xp,
+            level,
+            ..progression
+        });
+}
+
+#[reducer]
+/// Admins invoke this reducer to change how much XP users can earn from messages per hour.
+pub fn set_xp_hourly_cap(ctx: &ReducerContext, cap: u32) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    ctx.db.config().id().update(Config {
+        xp_hourly_cap: cap,
+        ..config(ctx)
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to adjust a user's XP by a positive or negative amount.
+pub fn grant_xp(ctx: &ReducerContext, target: Identity, amount: i64) -> Result<(), String> {
+    validate_admin(ctx)?;
+    if ctx.db.user().identity().find(target).is_none() {
+        return Err("Unknown user".to_string());
+    }
+
+    let progression = find_progression(ctx, target);
+    let xp = progression.xp.saturating_add_signed(amount);
+    set_xp(ctx, progression, xp);
+    audit(ctx, "grant_xp", Some(target), None);
+    Ok(())
+}
+
+/// Extends the sender's activity streak if they last posted yesterday, or restarts it after a gap.
+fn update_streak(ctx: &ReducerContext, user: User) {
+    let today = day_index(ctx.timestamp);
+    let streak_days = next_streak(user.last_active_day, user.streak_days, today);
+    ctx.db.user().identity().update(User {
+        streak_days,
+        last_active_day: Some(today),
+        ..user
+    });
+}
+
+/// The streak after activity on `today`: unchanged if already active today, one longer
+/// if last active yesterday, and otherwise starting over.
+fn next_streak(last_active_day: Option<u64>, streak_days: u32, today: u64) -> u32 {
+    match last_active_day {
+        Some(day) if day == today => streak_days,
+        Some(day) if day + 1 == today => streak_days + 1,
+        _ => 1,
+    }
+}
+
+/// Adds a sent message to the sender's lifetime totals.
+fn record_user_stats(ctx: &ReducerContext, sender: Identity, characters: u64) {
+    let stats = match ctx.db.user_stats().identity().find(sender) {
+        Some(stats) => UserStats {
+            messages_sent: stats.messages_sent + 1,
+            characters_sent: stats.characters_sent + characters,
+            last_message_at: ctx.timestamp,
+            ..stats
+        },
+        None => UserStats {
+            identity: sender,
+            messages_sent: 1,
+            characters_sent: characters,
+            reactions_received: 0,
+            first_message_at: ctx.timestamp,
+            last_message_at: ctx.timestamp,
+            dummy_join: true,
+        },
+    };
+    ctx.db.user_stats().identity().insert_or_update(stats);
+}
+
+/// Adjusts the author's count of reactions received, ignoring reactions to their own messages.
+fn record_reaction_received(ctx: &ReducerContext, author: Identity, added: bool) {
+    if author == ctx.sender {
+        return;
+    }
+    if let Some(stats) = ctx.db.user_stats().identity().find(author) {
+        let reactions_received = if added {
+            stats.reactions_received + 1
+        } else {
+            stats.reactions_received.saturating_sub(1)
+        };
+        ctx.db.user_stats().identity().update(UserStats {
+            reactions_received,
+            ..stats
+        });
+    }
+}
+
+/// Charges a message's bytes to the sender's budget for the current window, starting a new
+/// window if the last one has ended. The caller is responsible for storing the returned user.
+fn spend_byte_budget(
+    config: &Config,
+    user: User,
+    bytes: u64,
+    now: Timestamp,
+) -> Result<User, String> {
+    let window = Duration::from_secs(config.byte_budget_window_secs);
+    let in_window = user.byte_window_start.is_some_and(|start| {
+        now.duration_since(start)
+            .is_some_and(|elapsed| elapsed < window)
+    });
+    let (byte_window_start, spent) = if in_window {
+        (user.byte_window_start, user.bytes_in_window)
+    } else {
+        (Some(now), 0)
+    };
+    if spent + bytes > config.byte_budget_max_bytes {
+        return Err("Message rate limit exceeded".to_string());
+    }
+    Ok(User {
+        byte_window_start,
+        bytes_in_window: spent + bytes,
+        ..user
+    })
+}
+
+/// Rejects a message identical to the sender's previous one if it was sent too recently.
+fn check_duplicate(ctx: &ReducerContext, sender: Identity, text_hash: Hash) -> Result<(), String> {
+    let config = config(ctx);
+    if !config.duplicate_check_enabled {
+        return Ok(());
+    }
+    if let Some(state) = ctx.db.send_state().identity().find(sender) {
+        let window = Duration::from_secs(config.duplicate_window_secs);
+        let recent = ctx
+            .timestamp
+            .duration_since(state.last_sent)
+            .is_some_and(|elapsed| elapsed < window);
+        if recent && state.last_text_hash == text_hash {
+            return Err("Duplicate message".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Takes a message's text and checks if it's acceptable to send.
+fn validate_message(ctx: &ReducerContext, user: &User, text: String) -> Result<String, String> {
+    let config = config(ctx);
+    let max_len = config.max_message_len as usize;
+    if text.is_empty() {
+        Err("Messages must not be empty".to_string())
+    } else if grapheme_len(&text) > max_len {
+        Err(format!("Messages must be at most {max_len} characters"))
+    } else if has_disallowed_control_chars(&text) {
+        Err("Messages must not contain control characters".to_string())
+    } else if !config.allow_links && !is_moderator(user) && find_links(&text).next().is_some() {
+        Err("Links are not allowed".to_string())
+    } else if find_links(&text).any(|link| link.len() > MAX_LINK_LEN) {
+        Err(format!("Links must be at most {MAX_LINK_LEN} characters"))
+    } else {
+        Ok(text)
+    }
+}
+
+const MAX_LINK_LEN: usize = 512;
+
+/// Records the first `MAX_MESSAGE_LINKS` links in a message, so clients can render previews.
+fn store_links(ctx: &ReducerContext, message: &Message) {
+    for url in find_links(&message.text).take(MAX_MESSAGE_LINKS) {
+        ctx.db.message_link().insert(MessageLink {
+            id: 0,
+            message_id: message.id,
+            url: url.to_string(),
+        });
+    }
+}
+
+/// Yields every http(s) URL in the text, from its scheme up to the next whitespace.
+/// Bare domains without a scheme aren't treated as links.
+fn find_links(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace().filter_map(|word| {
+        let lower = word.to_ascii_lowercase();
+        let start = ["http://", "https://"]
+            .iter()
+            .filter_map(|scheme| lower.find(scheme))
+            .min()?;
+        Some(&word[start..])
+    })
+}
+
+/// Line breaks and tabs are the only control characters allowed in user text.
+fn has_disallowed_control_chars(text: &str) -> bool {
+    text.chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+}
+
+#[reducer]
+/// Clients invoke this reducer to edit the text of a message they sent.
+/// Regular users may only do so within the configured edit window.
+pub fn edit_message(ctx: &ReducerContext, message_id: u64, text: String) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let Some(message) = ctx.db.message().id().find(message_id) else {
+        return Err("Cannot edit unknown message".to_string());
+    };
+    if message.sender != ctx.sender {
+        return Err("Cannot edit another user's message".to_string());
+    }
+    if !is_moderator(&user) {
+        check_window(
+            message.sent,
+            ctx.timestamp,
+            config(ctx).edit_window_mins,
+            "edited",
+        )?;
+    }
+
+    let text = validate_message(ctx, &user, expand_shortcodes(ctx, &text))?;
+    let mentions = resolve_mentions(ctx, &text);
+    if mentions.len() > config(ctx).max_mentions as usize {
+        return Err("Too many mentions".to_string());
+    }
+    let message = ctx.db.message().id().update(Message {
+        text,
+        edited_at: Some(ctx.timestamp),
+        ..message
+    });
+    ctx.db.message_link().message_id().delete(message.id);
+    store_links(ctx, &message);
+
+    // Users who are still mentioned keep their notification rather than getting a second one.
+    ctx.db.mention().message_id().delete(message.id);
+    let notifications: Vec<Notification> = ctx
+        .db
+        .notification()
+        .message_id()
+        .filter(message.id)
+        .collect();
+    for id in stale_notifications(&notifications, &mentions) {
+        ctx.db.notification().id().delete(id);
+    }
+    let notified: Vec<Identity> = ctx
+        .db
+        .notification()
+        .message_id()
+        .filter(message.id)
+        .map(|notification| notification.recipient)
+        .collect();
+    notify_mentions(ctx, &message, &mentions, &notified);
+    Ok(())
+}
+
+/// The ids of the notifications about an edited message whose recipients it no longer mentions.
+fn stale_notifications(notifications: &[Notification], mentions: &[Identity]) -> Vec<u64> {
+    notifications
+        .iter()
+        .filter(|notification| !mentions.contains(&notification.recipient))
+        .map(|notification| notification.id)
+        .collect()
+}
+
+#[reducer]
+/// Clients invoke this reducer to delete a message.
+/// Authors can delete their own messages; moderators can delete others' messages.
+pub fn delete_message(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let Some(message) = ctx.db.message().id().find(message_id) else {
+        return Err("Cannot delete unknown message".to_string());
+    };
+    if message.sender == ctx.sender {
+        if !is_moderator(&user) {
+            check_window(
+                message.sent,
+                ctx.timestamp,
+                config(ctx).delete_window_mins,
+                "deleted",
+            )?;
+        }
+    } else {
+        let channel = ctx.db.channel().id().find(message.channel_id);
+        let allowed = is_moderator(&user)
+            || channel.is_some_and(|channel| can_moderate_channel(ctx, &user, &channel));
+        if !allowed {
+            return Err("Cannot delete another user's message".to_string());
+        }
+        let channel_id = (message.channel_id != GLOBAL_CHANNEL_ID).then_some(message.channel_id);
+        audit(ctx, "delete_message", Some(message.sender), channel_id);
+    }
+
+    delete_message_cascade(ctx, message_id);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to delete every message they've sent.
+pub fn clear_my_messages(ctx: &ReducerContext) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let message_ids: Vec<u64> = ctx
+        .db
+        .message()
+        .sender()
+        .filter(ctx.sender)
+        .map(|message| message.id)
+        .collect();
+    for &message_id in &message_ids {
+        delete_message_cascade(ctx, message_id);
+    }
+    log::info!(
+        "Cleared {} messages from {:?}",
+        message_ids.len(),
+        ctx.sender.to_hex()
+    );
+    Ok(())
+}
+
+/// Fails if more than `window_mins` minutes have passed since the message was sent.
+/// A window of 0 never closes.
+fn check_window(
+    sent: Timestamp,
+    now: Timestamp,
+    window_mins: u64,
+    action: &str,
+) -> Result<(), String> {
+    let window = Duration::from_secs(window_mins * 60);
+    let expired = window_mins > 0
+        && now
+            .duration_since(sent)
+            .is_some_and(|elapsed| elapsed > window);
+    if expired {
+        let unit = if window_mins == 1 {
+            "minute"
+        } else {
+            "minutes"
+        };
+        Err(format!(
+            "Messages can only be {action} within {window_mins} {unit}"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Deletes a message along with every row that refers to it.
+fn delete_message_cascade(ctx: &ReducerContext, message_id: u64) {
+    ctx.db.reaction().message_id().delete(message_id);
+    ctx.db.reaction_count().message_id().delete(message_id);
+    ctx.db.notification().message_id().delete(message_id);
+    delete_message_copies(ctx, message_id);
+    ctx.db.mention().message_id().delete(message_id);
+    ctx.db.pin().message_id().delete(message_id);
+    ctx.db.seen_by().message_id().delete(message_id);
+    if ctx.db.message().id().delete(message_id) {
+        adjust_room_stats(ctx, 0, 0, -1);
+    }
+}
+
+/// Returns a message if it exists and the caller can see it.
+///
+/// Ephemeral messages are reported as expired from the moment they run out, both before
+/// and after `expire_messages` deletes them.
+fn find_readable_message(
+    ctx: &ReducerContext,
+    user: &User,
+    message_id: u64,
+) -> Result<Message, String> {
+    match ctx.db.message().id().find(message_id) {
+        Some(message) if can_read_message(ctx, user, &message) => {
+            if is_expired(message.expires_at, ctx.timestamp) {
+                Err("Message expired".to_string())
+            } else {
+                Ok(message)
+            }
+        }
+        Some(_) => Err("Unknown message".to_string()),
+        None => match ctx.db.expired_message().message_id().find(message_id) {
+            Some(expired)
+                if expired.channel_id == GLOBAL_CHANNEL_ID
+                    || is_channel_member(ctx, expired.channel_id, ctx.sender) =>
+            {
+                Err("Message expired".to_string())
+            }
+            _ => Err("Unknown message".to_string()),
+        },
+    }
+}
+
+fn find_reaction_count(
+    ctx: &ReducerContext,
+    message_id: u64,
+    emoji: &str,
+) -> Option<ReactionCount> {
+    ctx.db
+        .reaction_count()
+        .message_id()
+        .filter(message_id)
+        .find(|count| count.emoji == emoji)
+}
+
+/// Counts a reaction being added or removed in `reaction_count`.
+fn adjust_reaction_count(ctx: &ReducerContext, message_id: u64, emoji: &str, added: bool) {
+    let existing = find_reaction_count(ctx, message_id, emoji);
+    match (
+        next_reaction_count(existing.as_ref().map(|count| count.count), added),
+        existing,
+    ) {
+        (Some(next), Some(count)) => {
+            ctx.db.reaction_count().id().update(ReactionCount {
+                count: next,
+                ..count
+            });
+        }
+        (Some(next), None) => {
+            ctx.db.reaction_count().insert(ReactionCount {
+                id: 0,
+                message_id,
+                emoji: emoji.to_string(),
+                count: next,
+            });
+        }
+        (None, Some(count)) => {
+            ctx.db.reaction_count().id().delete(count.id);
+        }
+        (None, None) => {}
+    }
+}
+
+/// The count after a reaction is added or removed, where `None` means there's no row,
+/// either before or after.
+fn next_reaction_count(count: Option<u32>, added: bool) -> Option<u32> {
+    match (count, added) {
+        (Some(count), true) => Some(count + 1),
+        (None, true) => Some(1),
+        (Some(count), false) if count > 1 => Some(count - 1),
+        (_, false) => None,
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to react to a message with an emoji.
+pub fn add_reaction(ctx: &ReducerContext, message_id: u64, emoji: String) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let message = find_readable_message(ctx, &user, message_id)?;
+
+    let emoji = check_reaction_allowed(&emoji, |emoji| {
+        ctx.db
+            .allowed_reaction()
+            .emoji()
+            .find(emoji.to_string())
+            .is_some()
+    })?;
+    let already_reacted = ctx
+        .db
+        .reaction()
+        .message_id()
+        .filter(message_id)
+        .any(|reaction| reaction.identity == ctx.sender && reaction.emoji == emoji);
+    if already_reacted {
+        return Err("Already reacted with this emoji".to_string());
+    }
+
+    adjust_reaction_count(ctx, message_id, &emoji, true);
+    ctx.db.reaction().insert(Reaction {
+        id: 0,
+        message_id,
+        identity: ctx.sender,
+        emoji,
+        created_at: ctx.timestamp,
+    });
+    record_reaction_received(ctx, message.sender, true);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to ask the room a question with 2 to 10 answers.
+pub fn create_poll(
+    ctx: &ReducerContext,
+    question: String,
+    options: Vec<String>,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let question = validate_message(ctx, &user, question)?;
+    if !(MIN_POLL_OPTIONS..=MAX_POLL_OPTIONS).contains(&options.len()) {
+        return Err(format!(
+            "Polls must have between {MIN_POLL_OPTIONS} and {MAX_POLL_OPTIONS} options"
+        ));
+    }
+    let options = options
+        .into_iter()
+        .map(validate_poll_option)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let poll = ctx.db.poll().insert(Poll {
+        id: 0,
+        creator: ctx.sender,
+        question,
+        created_at: ctx.timestamp,
+        closed: false,
+        dummy_join: true,
+    });
+    for (option_index, text) in (0..).zip(options) {
+        ctx.db.poll_option().insert(PollOption {
+            id: 0,
+            poll_id: poll.id,
+            option_index,
+            text,
+            dummy_join: true,
+        });
+    }
+    Ok(())
+}
+
+fn validate_poll_option(option: String) -> Result<String, String> {
+    let option = option.trim().to_string();
+    if option.is_empty() {
+        Err("Poll options must not be empty".to_string())
+    } else if grapheme_len(&option) > MAX_POLL_OPTION_LEN {
+        Err(format!(
+            "Poll options must be at most {MAX_POLL_OPTION_LEN} characters"
+        ))
+    } else if has_disallowed_control_chars(&option) {
+        Err("Poll options must not contain control characters".to_string())
+    } else {
+        Ok(option)
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to vote in an open poll, replacing any earlier vote.
+pub fn vote(ctx: &ReducerContext, poll_id: u64, option_index: u32) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let Some(poll) = ctx.db.poll().id().find(poll_id) else {
+        return Err("Unknown poll".to_string());
+    };
+    if poll.closed {
+        return Err("This poll is closed".to_string());
+    }
+    let option_count = ctx.db.poll_option().poll_id().filter(poll_id).count();
+    if option_index as usize >= option_count {
+        return Err("Unknown poll option".to_string());
+    }
+
+    let existing = ctx
+        .db
+        .poll_vote()
+        .poll_id()
+        .filter(poll_id)
+        .find(|vote| vote.voter == ctx.sender);
+    match existing {
+        Some(vote) => {
+            ctx.db.poll_vote().id().update(PollVote {
+                option_index,
+                ..vote
+            });
+        }
+        None => {
+            ctx.db.poll_vote().insert(PollVote {
+                id: 0,
+                poll_id,
+                voter: ctx.sender,
+                option_index,
+                dummy_join: true,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Poll creators and moderators invoke this reducer to stop a poll from taking votes.
+pub fn close_poll(ctx: &ReducerContext, poll_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+
+    let Some(poll) = ctx.db.poll().id().find(poll_id) else {
+        return Err("Unknown poll".to_string());
+    };
+    if poll.creator != ctx.sender && !is_moderator(&user) {
+        return Err("Only the poll's creator or a moderator can close it".to_string());
+    }
+    if poll.closed {
+        return Err("This poll is already closed".to_string());
+    }
+    ctx.db.poll().id().update(Poll {
+        closed: true,
+        ..poll
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer once they've shown a message to the user.
+pub fn mark_seen(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    find_readable_message(ctx, &user, message_id)?;
+
+    let seen: Vec<SeenBy> = ctx.db.seen_by().message_id().filter(message_id).collect();
+    if let Some(sighting) = new_sighting(&seen, message_id, ctx.sender, ctx.timestamp) {
+        ctx.db.seen_by().insert(sighting);
+    }
+    Ok(())
+}
+
+/// The row recording that `identity` saw a message, or `None` if `seen` already has one.
+fn new_sighting(
+    seen: &[SeenBy],
+    message_id: u64,
+    identity: Identity,
+    now: Timestamp,
+) -> Option<SeenBy> {
+    if seen.iter().any(|seen| seen.identity == identity) {
+        return None;
+    }
+    Some(SeenBy {
+        id: 0,
+        message_id,
+        identity,
+        seen_at: now,
+    })
+}
+
+#[reducer]
+/// Moderators invoke this reducer to pin a message in its channel, or in the room as a whole.
+pub fn pin_message(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let message = find_readable_message(ctx, &user, message_id)?;
+    validate_can_pin(ctx, &user, &message)?;
+
+    if ctx.db.pin().message_id().find(message_id).is_some() {
+        return Err("Message is already pinned".to_string());
+    }
+    check_pin_room(ctx.db.pin().channel_id().filter(message.channel_id).count())?;
+
+    ctx.db.pin().insert(Pin {
+        id: 0,
+        message_id,
+        channel_id: message.channel_id,
+        pinned_by: ctx.sender,
+        pinned_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Moderators invoke this reducer to unpin a message.
+pub fn unpin_message(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let message = find_readable_message(ctx, &user, message_id)?;
+    validate_can_pin(ctx, &user, &message)?;
+
+    if !ctx.db.pin().message_id().delete(message_id) {
+        return Err("Message is not pinned".to_string());
+    }
+    Ok(())
+}
+
+/// Fails if a channel that already has `pinned` pins can't take another.
+fn check_pin_room(pinned: usize) -> Result<(), String> {
+    if pinned >= MAX_PINS {
+        return Err(format!(
+            "A channel can have at most {MAX_PINS} pinned messages"
+        ));
+    }
+    Ok(())
+}
+
+/// Pins in a channel are managed by its moderators, and in the room by global moderators.
+fn validate_can_pin(ctx: &ReducerContext, user: &User, message: &Message) -> Result<(), String> {
+    let allowed = is_moderator(user)
+        || ctx
+            .db
+            .channel()
+            .id()
+            .find(message.channel_id)
+            .is_some_and(|channel| can_moderate_channel(ctx, user, &channel));
+    if allowed {
+        Ok(())
+    } else {
+        Err("Only moderators may pin messages".to_string())
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to flag a message for moderation.
+pub fn report_message(ctx: &ReducerContext, message_id: u64, reason: String) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let message = find_readable_message(ctx, &user, message_id)?;
+    if message.sender == ctx.sender {
+        return Err("Cannot report your own message".to_string());
+    }
+
+    let reason = reason.trim().to_string();
+    if grapheme_len(&reason) > MAX_REPORT_REASON_LEN {
+        return Err(format!(
+            "Report reasons must be at most {MAX_REPORT_REASON_LEN} characters"
+        ));
+    }
+    let reports: Vec<Report> = ctx.db.report().message_id().filter(message_id).collect();
+    if already_reported(&reports, ctx.sender) {
+        return Err("You have already reported this message".to_string());
+    }
+
+    let mut reports = reports;
+    reports.push(ctx.db.report().insert(Report {
+        id: 0,
+        message_id,
+        reporter: ctx.sender,
+        reason,
+        created_at: ctx.timestamp,
+        status: ReportStatus::Open,
+        handled_by: None,
+        dummy_join: true,
+    }));
+
+    if !message.hidden && reaches_hide_threshold(&reports, config(ctx).report_hide_threshold) {
+        log::info!("Hiding message {message_id} after reaching the report threshold");
+        ctx.db.message().id().update(Message {
+            hidden: true,
+            ..message
+        });
+    }
+    Ok(())
+}
+
+/// Whether `reporter` has an open report among a message's reports.
+fn already_reported(reports: &[Report], reporter: Identity) -> bool {
+    reports
+        .iter()
+        .any(|report| report.reporter == reporter && report.status == ReportStatus::Open)
+}
+
+/// Whether a message's reports come from at least `threshold` distinct reporters, not counting
+/// dismissed reports. A threshold of 0 never hides messages.
+fn reaches_hide_threshold(reports: &[Report], threshold: u32) -> bool {
+    let reporters: HashSet<Identity> = reports
+        .iter()
+        .filter(|report| report.status != ReportStatus::Dismissed)
+        .map(|report| report.reporter)
+        .collect();
+    threshold > 0 && reporters.len() >= threshold as usize
+}
+
+#[reducer]
+/// Moderators invoke this reducer to act on a report, optionally deleting the reported message.
+pub fn resolve_report(ctx: &ReducerContext, report_id: u64, delete: bool) -> Result<(), String> {
+    let report = close_report(ctx, report_id, ReportStatus::Resolved)?;
+    if delete && ctx.db.message().id().find(report.message_id).is_some() {
+        delete_message(ctx, report.message_id)?;
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Moderators invoke this reducer to close a report without acting on it.
+pub fn dismiss_report(ctx: &ReducerContext, report_id: u64) -> Result<(), String> {
+    close_report(ctx, report_id, ReportStatus::Dismissed)?;
+    Ok(())
+}
+
+fn close_report(
+    ctx: &ReducerContext,
+    report_id: u64,
+    status: ReportStatus,
+) -> Result<Report, String> {
+    let user = validate_identity(ctx)?;
+    if !is_moderator(&user) {
+        return Err("Only moderators may handle reports".to_string());
+    }
+    let Some(report) = ctx.db.report().id().find(report_id) else {
+        return Err("Unknown report".to_string());
+    };
+    if report.status != ReportStatus::Open {
+        return Err("This report has already been handled".to_string());
+    }
+
+    let action = match status {
+        ReportStatus::Dismissed => "dismiss_report",
+        _ => "resolve_report",
+    };
+    audit(ctx, action, Some(report.reporter), None);
+    Ok(ctx.db.report().id().update(Report {
+        status,
+        handled_by: Some(ctx.sender),
+        ..report
+    }))
+}
+
+/// Trims a reaction, failing unless `is_allowed` accepts it.
+fn check_reaction_allowed(
+    emoji: &str,
+    is_allowed: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    let emoji = emoji.trim();
+    if is_allowed(emoji) {
+        Ok(emoji.to_string())
+    } else {
+        Err("That reaction is not allowed".to_string())
+    }
+}
+
+/// Checks an emoji an admin wants to allow as a reaction, returning it trimmed.
+fn validate_reaction_emoji(emoji: &str) -> Result<String, String> {
+    let emoji = emoji.trim().to_string();
+    if emoji.is_empty() {
+        Err("Reactions must not be empty".to_string())
+    } else if grapheme_len(&emoji) > MAX_REACTION_LEN || has_disallowed_control_chars(&emoji) {
+        Err("Invalid reaction".to_string())
+    } else {
+        Ok(emoji)
+    }
+}
+
+#[reducer]
+/// Admins invoke this reducer to add an emoji to the set users may react with.
+pub fn add_allowed_reaction(ctx: &ReducerContext, emoji: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let emoji = validate_reaction_emoji(&emoji)?;
+    if ctx.db.allowed_reaction().emoji().find(&emoji).is_none() {
+        ctx.db.allowed_reaction().insert(AllowedReaction { emoji });
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to add a `:code:` shortcode, or change the emoji of an existing one.
+pub fn set_shortcode(ctx: &ReducerContext, code: String, emoji: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let code = code.trim().trim_matches(':').to_string();
+    if code.is_empty() {
+        return Err("Shortcodes must not be empty".to_string());
+    }
+    if code.len() > MAX_SHORTCODE_LEN
+        || !code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '+' || c == '-')
+    {
+        return Err("Invalid shortcode".to_string());
+    }
+    let emoji = emoji.trim().to_string();
+    if emoji.is_empty()
+        || grapheme_len(&emoji) > MAX_REACTION_LEN
+        || has_disallowed_control_chars(&emoji)
+    {
+        return Err("Invalid shortcode emoji".to_string());
+    }
+    ctx.db
+        .shortcode()
+        .code()
+        .insert_or_update(Shortcode { code, emoji });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to remove a shortcode; messages already sent keep their emoji.
+pub fn remove_shortcode(ctx: &ReducerContext, code: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let code = code.trim().trim_matches(':').to_string();
+    if !ctx.db.shortcode().code().delete(code) {
+        return Err("Unknown shortcode".to_string());
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to stop users from adding new reactions with an emoji.
+/// Existing reactions with it are kept.
+pub fn remove_allowed_reaction(ctx: &ReducerContext, emoji: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if !ctx
+        .db
+        .allowed_reaction()
+        .emoji()
+        .delete(emoji.trim().to_string())
+    {
+        return Err("That reaction is not in the allowed set".to_string());
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to take back one of their reactions.
+pub fn remove_reaction(ctx: &ReducerContext, message_id: u64, emoji: String) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let emoji = emoji.trim();
+    let reaction = ctx
+        .db
+        .reaction()
+        .message_id()
+        .filter(message_id)
+        .find(|reaction| reaction.identity == ctx.sender && reaction.emoji == emoji);
+    let Some(reaction) = reaction else {
+        return Err("No such reaction".to_string());
+    };
+
+    delete_reaction(ctx, reaction);
+    Ok(())
+}
+
+/// Deletes a reaction, keeping its message's `reaction_count` and its author's stats in step.
+fn delete_reaction(ctx: &ReducerContext, reaction: Reaction) {
+    ctx.db.reaction().id().delete(reaction.id);
+    adjust_reaction_count(ctx, reaction.message_id, &reaction.emoji, false);
+    if let Some(message) = ctx.db.message().id().find(reaction.message_id) {
+        record_reaction_received(ctx, message.sender, false);
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to search messages for a case-insensitive substring.
+/// The most recent matches replace the caller's previous rows in `search_result`.
+pub fn search_messages(ctx: &ReducerContext, query: String) -> Result<(), String> {
+    let user = validate_reader(ctx)?;
+
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        return Err("Search query must not be empty".to_string());
+    }
+
+    ctx.db.search_result().owner().delete(ctx.sender);
+
+    let readable = ctx
+        .db
+        .message()
+        .iter()
+        .filter(|message| can_read_message(ctx, &user, message));
+    for message in search_hits(readable, &query) {
+        ctx.db.search_result().insert(SearchResult {
+            id: 0,
+            owner: ctx.sender,
+            message_id: message.id,
+            channel_id: message.channel_id,
+            sender: message.sender,
+            sent: message.sent,
+            text: message.text,
+        });
+    }
+    Ok(())
+}
+
+/// The most recent `MAX_SEARCH_RESULTS` messages containing the lowercase `query`, ignoring case.
+fn search_hits(messages: impl IntoIterator<Item = Message>, query: &str) -> Vec<Message> {
+    let mut matches: Vec<Message> = messages
+        .into_iter()
+        .filter(|message| message.text.to_lowercase().contains(query))
+        .collect();
+    matches.sort_by_key(|message| std::cmp::Reverse(message.sent));
+    matches.truncate(MAX_SEARCH_RESULTS);
+    matches
+}
+
+#[reducer]
+/// Clients invoke this reducer to find users whose username starts with a prefix, ignoring case.
+/// The matches replace the caller's previous rows in `user_search_result`.
+pub fn search_users(ctx: &ReducerContext, prefix: String) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    ctx.db.user_search_result().owner().delete(ctx.sender);
+
+    let prefix = normalize_name(prefix.trim());
+    if prefix.is_empty() {
+        return Ok(());
+    }
+
+    for user in users_with_prefix(ctx.db.user().iter(), &prefix) {
+        let Some(username) = user.username else {
+            continue;
+        };
+        ctx.db.user_search_result().insert(UserSearchResult {
+            id: 0,
+            owner: ctx.sender,
+            identity: user.identity,
+            username,
+            nickname: user.nickname,
+        });
+    }
+    Ok(())
+}
+
+/// The users whose normalized username starts with `prefix`, in username order and capped
+/// at `MAX_USER_SEARCH_RESULTS`.
+fn users_with_prefix(users: impl IntoIterator<Item = User>, prefix: &str) -> Vec<User> {
+    let mut matches: Vec<User> = users
+        .into_iter()
+        .filter(|user| {
+            user.username_normalized
+                .as_ref()
+                .is_some_and(|username| username.starts_with(prefix))
+        })
+        .collect();
+    matches.sort_by(|a, b| a.username_normalized.cmp(&b.username_normalized));
+    matches.truncate(MAX_USER_SEARCH_RESULTS);
+    matches
+}
+
+#[reducer]
+/// Clients invoke this reducer to load another user's public profile into `profile_view`,
+/// replacing the previously loaded one.
+pub fn load_profile(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let user = match ctx.db.user().identity().find(target) {
+        Some(user) if user.authorized => user,
+        _ => return Err("Unknown user".to_string()),
+    };
+    ctx.db.profile_view().owner().insert_or_update(ProfileView {
+        owner: ctx.sender,
+        identity: target,
+        account_age_secs: account_age_secs(&user, ctx.timestamp),
+        username: user.username,
+        nickname: user.nickname,
+        color: user.color,
+        avatar_url: user.avatar_url,
+        online: user.online,
+        first_seen: user.first_seen,
+    });
+    Ok(())
+}
+
+/// Counts the messages after the read marker, if any. Without one, every message is unread.
+fn count_unread(message_ids: impl IntoIterator<Item = u64>, last_read: Option<u64>) -> u64 {
+    message_ids
+        .into_iter()
+        .filter(|&id| last_read.is_none_or(|last_read| id > last_read))
+        .count() as u64
+}
+
+/// How many messages in the channel the user hasn't read, not counting their own or hidden ones.
+fn unread_count(ctx: &ReducerContext, identity: Identity, channel_id: u64) -> u64 {
+    let last_read = ctx
+        .db
+        .read_marker()
+        .identity()
+        .filter(identity)
+        .find(|marker| marker.channel_id == channel_id)
+        .map(|marker| marker.last_read_message_id);
+    let message_ids = ctx
+        .db
+        .message()
+        .channel_id()
+        .filter(channel_id)
+        .filter(|message| message.sender != identity && !message.hidden)
+        .map(|message| message.id);
+    count_unread(message_ids, last_read)
+}
+
+#[reducer]
+/// Clients invoke this reducer to load their unread counts for the room and each of their
+/// channels into `unread_count`, replacing the previously loaded counts.
+pub fn load_unread_counts(ctx: &ReducerContext) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    ctx.db.unread_count().owner().delete(ctx.sender);
+    let channel_ids: Vec<u64> = std::iter::once(GLOBAL_CHANNEL_ID)
+        .chain(
+            ctx.db
+                .channel_member()
+                .identity()
+                .filter(ctx.sender)
+                .map(|member| member.channel_id),
+        )
+        .collect();
+    for channel_id in channel_ids {
+        ctx.db.unread_count().insert(UnreadCount {
+            id: 0,
+            owner: ctx.sender,
+            channel_id,
+            unread: unread_count(ctx, ctx.sender, channel_id),
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to load the members of a channel they're in, sorted by name,
+/// into `loaded_channel_member`, replacing the previously loaded roster.
+pub fn load_channel_members(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+    if ctx.db.channel().id().find(channel_id).is_none() {
+        return Err("Unknown channel".to_string());
+    }
+
+    let members = ctx
+        .db
+        .channel_member()
+        .channel_id()
+        .filter(channel_id)
+        .filter_map(|member| {
+            let user = ctx.db.user().identity().find(member.identity)?;
+            Some((member, user))
+        });
+    let roster = channel_roster(ctx.sender, members)?;
+
+    ctx.db.loaded_channel_member().owner().delete(ctx.sender);
+    for member in roster {
+        ctx.db.loaded_channel_member().insert(member);
+    }
+    Ok(())
+}
+
+/// The `owner`'s copy of a channel's members, sorted by name. Fails unless the owner is one
+/// of the members.
+fn channel_roster(
+    owner: Identity,
+    members: impl IntoIterator<Item = (ChannelMember, User)>,
+) -> Result<Vec<LoadedChannelMember>, String> {
+    let mut roster: Vec<LoadedChannelMember> = members
+        .into_iter()
+        .map(|(member, user)| LoadedChannelMember {
+            id: 0,
+            owner,
+            channel_id: member.channel_id,
+            identity: member.identity,
+            name: member.nickname.unwrap_or_else(|| display_name(&user)),
+            online: user.online,
+            is_moderator: member.is_moderator,
+        })
+        .collect();
+    if !roster.iter().any(|member| member.identity == owner) {
+        return Err("Not a member of this channel".to_string());
+    }
+    roster.sort_by_key(|member| normalize_name(&member.name));
+    Ok(roster)
+}
+
+#[reducer]
+/// Clients invoke this reducer to load the messages sent between `start` and `end`, inclusive.
+/// The matching messages replace the caller's previous rows in `loaded_message`.
+pub fn load_messages_range(
+    ctx: &ReducerContext,
+    start: Timestamp,
+    end: Timestamp,
+) -> Result<(), String> {
+    let user = validate_reader(ctx)?;
+
+    if end < start {
+        return Err("Range end must not be before its start".to_string());
+    }
+
+    ctx.db.loaded_message().owner().delete(ctx.sender);
+
+    let readable = ctx
+        .db
+        .message()
+        .iter()
+        .filter(|message| can_read_message(ctx, &user, message));
+    for message in messages_in_range(readable, start, end) {
+        ctx.db.loaded_message().insert(LoadedMessage {
+            id: 0,
+            owner: ctx.sender,
+            message_id: message.id,
+            channel_id: message.channel_id,
+            sender: message.sender,
+            sent: message.sent,
+            text: message.text,
+        });
+    }
+    Ok(())
+}
+
+/// The messages sent between `start` and `end`, inclusive, oldest first.
+fn messages_in_range(
+    messages: impl IntoIterator<Item = Message>,
+    start: Timestamp,
+    end: Timestamp,
+) -> Vec<Message> {
+    let mut messages: Vec<Message> = messages
+        .into_iter()
+        .filter(|message| start <= message.sent && message.sent <= end)
+        .collect();
+    messages.sort_by_key(|message| message.sent);
+    messages
+}
+
+#[reducer]
+/// Clients invoke this reducer to load up to `limit` messages older than `before_id`,
+/// or the latest messages when `before_id` is `None`, newest first.
+/// The page replaces the caller's previous rows in `loaded_message`.
+pub fn load_page(ctx: &ReducerContext, before_id: Option<u64>, limit: u32) -> Result<(), String> {
+    let user = validate_reader(ctx)?;
+
+    ctx.db.loaded_message().owner().delete(ctx.sender);
+
+    let readable = ctx
+        .db
+        .message()
+        .iter()
+        .filter(|message| can_read_message(ctx, &user, message));
+    for message in page_before(readable, before_id, limit) {
+        ctx.db.loaded_message().insert(LoadedMessage {
+            id: 0,
+            owner: ctx.sender,
+            message_id: message.id,
+            channel_id: message.channel_id,
+            sender: message.sender,
+            sent: message.sent,
+            text: message.text,
+        });
+    }
+    Ok(())
+}
+
+/// Up to `limit`, at most `MAX_PAGE_SIZE`, of the messages older than `before_id`,
+/// or the latest ones when it's `None`, newest first.
+fn page_before(
+    messages: impl IntoIterator<Item = Message>,
+    before_id: Option<u64>,
+    limit: u32,
+) -> Vec<Message> {
+    let mut messages: Vec<Message> = messages
+        .into_iter()
+        .filter(|message| before_id.is_none_or(|before_id| message.id < before_id))
+        .collect();
+    messages.sort_by_key(|message| std::cmp::Reverse(message.id));
+    messages.truncate(limit.min(MAX_PAGE_SIZE) as usize);
+    messages
+}
+
+/// Schedules the next daily rollup for shortly after the coming UTC midnight.
+fn schedule_daily_rollup(ctx: &ReducerContext) {
+    let next_midnight = (day_index(ctx.timestamp) as i64 + 1) * MICROS_PER_DAY;
+    let run_at = Timestamp::from_micros_since_unix_epoch(next_midnight + ROLLUP_DELAY_MICROS);
+    ctx.db.daily_stats_schedule().insert(DailyStatsSchedule {
+        scheduled_id: 0,
+        scheduled_at: run_at.into(),
+    });
+}
+
+#[reducer]
+/// Aggregates the previous UTC day's messages into `daily_stats`, then schedules the next run.
+pub fn rollup_daily_stats(
+    ctx: &ReducerContext,
+    _schedule: DailyStatsSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `rollup_daily_stats` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let day = day_index(ctx.timestamp).saturating_sub(1);
+    let mut messages_count = 0;
+    let mut senders = HashSet::new();
+    // `Timestamp` can't be used as an index key in reducers, so this scans.
+    for message in ctx.db.message().iter().filter(|m| day_index(m.sent) == day) {
+        messages_count += 1;
+        senders.insert(message.sender);
+    }
+    ctx.db.daily_stats().day().insert_or_update(DailyStats {
+        day,
+        messages_count,
+        active_users: senders.len() as u64,
+    });
+
+    let mut days: Vec<u64> = ctx.db.daily_stats().iter().map(|stats| stats.day).collect();
+    if days.len() > DAILY_STATS_RETENTION {
+        days.sort_unstable();
+        for day in &days[..days.len() - DAILY_STATS_RETENTION] {
+            ctx.db.daily_stats().day().delete(day);
+        }
+    }
+
+    schedule_daily_rollup(ctx);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to start a private conversation with other users.
+pub fn create_conversation(
+    ctx: &ReducerContext,
+    participants: Vec<Identity>,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+    open_conversation(ctx, participants)?;
+    Ok(())
+}
+
+/// Creates a conversation between the caller and the given participants. A caller and one
+/// other participant get their existing one-on-one conversation back, if they have one.
+fn open_conversation(
+    ctx: &ReducerContext,
+    participants: Vec<Identity>,
+) -> Result<Conversation, String> {
+    if participants.is_empty() {
+        return Err("Conversations need at least one other participant".to_string());
+    }
+    let mut members = vec![ctx.sender];
+    for identity in participants {
+        if members.contains(&identity) {
+            return Err("Participants must not be listed more than once".to_string());
+        }
+        validate_message_recipient(ctx, identity)?;
+        match ctx.db.user().identity().find(identity) {
+            Some(user) if user.authorized => members.push(identity),
+            _ => return Err("Cannot add an unauthorized user to a conversation".to_string()),
+        }
+    }
+    let max_participants = config(ctx).max_conversation_participants as usize;
+    if members.len() > max_participants {
+        return Err(format!(
+            "Conversations may have at most {max_participants} participants"
+        ));
+    }
+
+    if let [a, b] = members[..] {
+        let existing = find_direct_conversation(ctx, a, b)
+            .and_then(|conversation_id| ctx.db.conversation().id().find(conversation_id));
+        if let Some(conversation) = existing {
+            return Ok(conversation);
+        }
+    }
+
+    let conversation = ctx.db.conversation().insert(Conversation {
+        id: 0,
+        creator: ctx.sender,
+        participants: members.clone(),
+        created_at: ctx.timestamp,
+        last_message_at: ctx.timestamp,
+    });
+    if let [a, b] = members[..] {
+        let (user_a, user_b) = ordered_pair(a, b);
+        ctx.db.direct_conversation().insert(DirectConversation {
+            id: 0,
+            user_a,
+            user_b,
+            conversation_id: conversation.id,
+        });
+    }
+    for identity in members {
+        ctx.db.conversation_member().insert(ConversationMember {
+            id: 0,
+            conversation_id: conversation.id,
+            identity,
+        });
+    }
+    Ok(conversation)
+}
+
+/// Fails if the recipient doesn't exist or has blocked the caller, without saying which.
+/// Blocking is one-directional: users can still message people they've blocked.
+fn validate_message_recipient(ctx: &ReducerContext, recipient: Identity) -> Result<(), String> {
+    let exists = ctx.db.user().identity().find(recipient).is_some();
+    let blocks: Vec<Block> = ctx.db.block().blocker().filter(recipient).collect();
+    check_recipient(ctx.sender, recipient, exists, &blocks)
+}
+
+/// `validate_message_recipient` against the given blocks.
+fn check_recipient(
+    sender: Identity,
+    recipient: Identity,
+    recipient_exists: bool,
+    blocks: &[Block],
+) -> Result<(), String> {
+    let blocked = blocks
+        .iter()
+        .any(|block| block.blocker == recipient && block.blocked == sender);
+    if !recipient_exists || blocked {
+        Err("You cannot message this user".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to message another user one-on-one,
+/// reusing their existing one-on-one conversation if there is one.
+pub fn send_private_message(
+    ctx: &ReducerContext,
+    recipient: Identity,
+    text: String,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+    if recipient == ctx.sender {
+        return Err("Cannot send a private message to yourself".to_string());
+    }
+    validate_message_recipient(ctx, recipient)?;
+
+    let conversation_id = match find_direct_conversation(ctx, ctx.sender, recipient) {
+        Some(conversation_id) => conversation_id,
+        None => open_conversation(ctx, vec![recipient])?.id,
+    };
+    send_conversation_message(ctx, conversation_id, text)
+}
+
+/// The id of the one-on-one conversation between two users, if they have one.
+fn find_direct_conversation(ctx: &ReducerContext, a: Identity, b: Identity) -> Option<u64> {
+    let (user_a, _) = ordered_pair(a, b);
+    direct_conversation_id(ctx.db.direct_conversation().user_a().filter(user_a), a, b)
+}
+
+/// Two users' identities in the order `direct_conversation` stores them.
+fn ordered_pair(a: Identity, b: Identity) -> (Identity, Identity) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// The id of the conversation among `rows` between `a` and `b`, in either order.
+fn direct_conversation_id(
+    rows: impl IntoIterator<Item = DirectConversation>,
+    a: Identity,
+    b: Identity,
+) -> Option<u64> {
+    let (user_a, user_b) = ordered_pair(a, b);
+    rows.into_iter()
+        .find(|row| row.user_a == user_a && row.user_b == user_b)
+        .map(|row| row.conversation_id)
+}
+
+/// Checks that the caller is a member of the given conversation.
+fn validate_conversation_member(ctx: &ReducerContext, conversation_id: u64) -> Result<(), String> {
+    let is_member = ctx
+        .db
+        .conversation_member()
+        .conversation_id()
+        .filter(conversation_id)
+        .any(|member| member.identity == ctx.sender);
+    if is_member {
+        Ok(())
+    } else {
+        Err("Not a member of this conversation".to_string())
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to mark every message in a channel they're in as read,
+/// or in the room as a whole for `GLOBAL_CHANNEL_ID`.
+pub fn mark_channel_read(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+    if channel_id != GLOBAL_CHANNEL_ID {
+        validate_channel_member(ctx, channel_id)?;
+    }
+
+    let Some(latest) = ctx
+        .db
+        .message()
+        .channel_id()
+        .filter(channel_id)
+        .map(|message| message.id)
+        .max()
+    else {
+        return Ok(());
+    };
+    let existing = ctx
+        .db
+        .read_marker()
+        .identity()
+        .filter(ctx.sender)
+        .find(|marker| marker.channel_id == channel_id);
+    match existing {
+        Some(marker) if marker.last_read_message_id >= latest => {}
+        Some(marker) => {
+            ctx.db.read_marker().id().update(ReadMarker {
+                last_read_message_id: latest,
+                ..marker
+            });
+        }
+        None => {
+            ctx.db.read_marker().insert(ReadMarker {
+                id: 0,
+                identity: ctx.sender,
+                channel_id,
+                last_read_message_id: latest,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to mark a conversation as read up to and including a message.
+/// Markers never move backwards.
+pub fn mark_conversation_read(
+    ctx: &ReducerContext,
+    conversation_id: u64,
+    message_id: u64,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+    validate_conversation_member(ctx, conversation_id)?;
+    match ctx.db.conversation_message().id().find(message_id) {
+        Some(message) if message.conversation_id == conversation_id => {}
+        _ => return Err("Unknown message".to_string()),
+    }
+
+    let hidden = notification_prefs(ctx, ctx.sender).hide_read_receipts;
+    let existing = ctx
+        .db
+        .conversation_read_marker()
+        .identity()
+        .filter(ctx.sender)
+        .find(|marker| marker.conversation_id == conversation_id);
+    match existing {
+        Some(marker) if marker.last_read_message_id >= message_id => {}
+        Some(marker) => {
+            ctx.db
+                .conversation_read_marker()
+                .id()
+                .update(ConversationReadMarker {
+                    last_read_message_id: message_id,
+                    hidden,
+                    ..marker
+                });
+        }
+        None => {
+            ctx.db
+                .conversation_read_marker()
+                .insert(ConversationReadMarker {
+                    id: 0,
+                    conversation_id,
+                    identity: ctx.sender,
+                    last_read_message_id: message_id,
+                    hidden,
+                });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to send a message to a conversation they're a member of.
+pub fn send_conversation_message(
+    ctx: &ReducerContext,
+    conversation_id: u64,
+    text: String,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    validate_conversation_member(ctx, conversation_id)?;
+    let members: Vec<Identity> = ctx
+        .db
+        .conversation_member()
+        .conversation_id()
+        .filter(conversation_id)
+        .map(|member| member.identity)
+        .collect();
+    // In a one-on-one conversation, a block stops new messages but keeps the history.
+    if let [a, b] = members[..] {
+        let other = if a == ctx.sender { b } else { a };
+        validate_message_recipient(ctx, other)?;
+    }
+
+    // Conversation messages go through the same checks as room messages, but can't be
+    // held for approval, so a sender who still needs approval is turned down instead.
+    let text = validate_message(ctx, &user, expand_shortcodes(ctx, &text))?;
+    check_may_send(ctx, &user)?;
+    check_no_approval_needed(ctx, &user)?;
+    let Some(user) = pass_spam_checks(ctx, user, &text)? else {
+        return Ok(());
+    };
+
+    let hash = hash_bytes(text.trim());
+    let message = ctx.db.conversation_message().insert(ConversationMessage {
+        id: 0,
+        conversation_id,
+        sender: ctx.sender,
+        sent: ctx.timestamp,
+        text,
+    });
+    count_message(ctx, user, &message.text);
+    record_send(ctx, ctx.sender, hash);
+    if let Some(conversation) = ctx.db.conversation().id().find(conversation_id) {
+        ctx.db
+            .conversation()
+            .id()
+            .update(touch_conversation(conversation, ctx.timestamp));
+    }
+    Ok(())
+}
+
+/// A conversation with a message sent to it at `now`.
+fn touch_conversation(conversation: Conversation, now: Timestamp) -> Conversation {
+    Conversation {
+        last_message_at: now,
+        ..conversation
+    }
+}
+
+/// Whether the caller, who passed `validate_reader`, may read a message under the visibility filters.
+fn can_read_message(ctx: &ReducerContext, user: &User, message: &Message) -> bool {
+    if message.hidden {
+        return user.admin;
+    }
+    message.channel_id == GLOBAL_CHANNEL_ID
+        || is_channel_member(ctx, message.channel_id, ctx.sender)
+}
+
+fn channel_membership(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    identity: Identity,
+) -> Option<ChannelMember> {
+    ctx.db
+        .channel_member()
+        .channel_id()
+        .filter(channel_id)
+        .find(|member| member.identity == identity)
+}
+
+fn is_channel_member(ctx: &ReducerContext, channel_id: u64, identity: Identity) -> bool {
+    channel_membership(ctx, channel_id, identity).is_some()
+}
+
+/// Whether the caller may moderate a channel: its creator, its moderators, and admins can.
+fn can_moderate_channel(ctx: &ReducerContext, user: &User, channel: &Channel) -> bool {
+    channel.creator == ctx.sender
+        || user.admin
+        || channel_membership(ctx, channel.id, ctx.sender).is_some_and(|member| member.is_moderator)
+}
+
+/// Records a moderation action taken by the caller.
+fn audit(ctx: &ReducerContext, action: &str, target: Option<Identity>, channel_id: Option<u64>) {
+    audit_as(ctx, ctx.sender, action, target, channel_id);
+}
+
+/// Records a moderation action taken by `actor`, which is the module itself for automatic ones.
+fn audit_as(
+    ctx: &ReducerContext,
+    actor: Identity,
+    action: &str,
+    target: Option<Identity>,
+    channel_id: Option<u64>,
+) {
+    ctx.db.audit_log().insert(AuditLog {
+        id: 0,
+        actor,
+        action: action.to_string(),
+        target,
+        channel_id,
+        created_at: ctx.timestamp,
+        dummy_join: true,
+    });
+}
+
+/// Returns the channel if the caller is a member of it.
+fn validate_channel_member(ctx: &ReducerContext, channel_id: u64) -> Result<Channel, String> {
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    if is_channel_member(ctx, channel_id, ctx.sender) {
+        Ok(channel)
+    } else {
+        Err("Not a member of this channel".to_string())
+    }
+}
+
+/// Takes a channel name and checks if it's acceptable.
+fn validate_channel_name(ctx: &ReducerContext, name: String) -> Result<String, String> {
+    check_channel_name(name, config(ctx).max_name_len as usize, |name| {
+        ctx.db.channel().name().find(name.to_string()).is_some()
+    })
+}
+
+/// `validate_channel_name` with names of up to `max_len` grapheme clusters allowed, and
+/// `taken` telling which names other channels already have.
+fn check_channel_name(
+    name: String,
+    max_len: usize,
+    taken: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        Err("Channel names must not be empty".to_string())
+    } else if grapheme_len(&name) > max_len {
+        Err(format!(
+            "Channel names must be at most {max_len} characters"
+        ))
+    } else if taken(&name) {
+        Err("Channel name already taken".to_string())
+    } else {
+        Ok(name)
+    }
+}
+
+/// Fails unless the caller created the channel or is an admin, naming what they tried to do.
+fn check_channel_owner(channel: &Channel, caller: &User, action: &str) -> Result<(), String> {
+    if channel.creator == caller.identity || caller.admin {
+        Ok(())
+    } else {
+        Err(format!(
+            "Only the channel creator or an admin may {action} a channel"
+        ))
+    }
+}
+
+/// Fails for the channel new users join by default, which must always exist.
+fn check_channel_deletable(channel_id: u64, default_channel_id: u64) -> Result<(), String> {
+    if channel_id == default_channel_id {
+        Err("The default channel cannot be deleted".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// How a user is referred to in system messages.
+fn display_name(user: &User) -> String {
+    match user.nickname.as_ref().or(user.username.as_ref()) {
+        Some(name) => name.clone(),
+        None => user.identity.to_hex().to_string()[..8].to_string(),
+    }
+}
+
+/// Inserts a message authored by the module itself, e.g. to record a channel change in its history.
+fn post_system_message(ctx: &ReducerContext, channel_id: u64, text: String) {
+    ctx.db.message().insert(Message {
+        id: 0,
+        channel_id,
+        sender: ctx.identity(),
+        sent: ctx.timestamp,
+        text,
+        edited_at: None,
+        hidden: false,
+        expires_at: None,
+        forwarded_from: None,
+        forwarded_message_id: None,
+        seq: next_seq(ctx, channel_id),
+        kind: MessageKind::System,
+        dummy_join: true,
+    });
+    adjust_room_stats(ctx, 0, 0, 1);
+}
+
+#[reducer]
+/// Clients invoke this reducer to create a channel, which they join automatically.
+pub fn create_channel(
+    ctx: &ReducerContext,
+    name: String,
+    visibility: ChannelVisibility,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let name = validate_channel_name(ctx, name)?;
+    let position = sibling_channels(ctx, None).len() as u32;
+    let channel = ctx.db.channel().insert(Channel {
+        id: 0,
+        name,
+        creator: ctx.sender,
+        created_at: ctx.timestamp,
+        topic: None,
+        invite_only: visibility == ChannelVisibility::InviteOnly,
+        archived: false,
+        category_id: None,
+        position,
+        next_seq: 1,
+    });
+    add_channel_member(ctx, channel.id, ctx.sender);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to join a public channel, or an invite-only one they were
+/// invited to, which uses up the invite.
+pub fn join_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    validate_not_archived(&channel)?;
+    if is_channel_member(ctx, channel_id, ctx.sender) {
+        return Err("Already a member of this channel".to_string());
+    }
+    let invites: Vec<ChannelInvite> = ctx
+        .db
+        .channel_invite()
+        .invitee()
+        .filter(ctx.sender)
+        .collect();
+    if let Some(invite_id) = invite_to_consume(&channel, &invites)? {
+        ctx.db.channel_invite().id().delete(invite_id);
+    }
+    add_channel_member(ctx, channel_id, ctx.sender);
+    Ok(())
+}
+
+/// Decides whether a user holding `invites` may join a channel. Returns the id of the invite
+/// that joining uses up, or `None` for a public channel, which needs no invite.
+fn invite_to_consume(channel: &Channel, invites: &[ChannelInvite]) -> Result<Option<u64>, String> {
+    if !channel.invite_only {
+        return Ok(None);
+    }
+    invites
+        .iter()
+        .find(|invite| invite.channel_id == channel.id)
+        .map(|invite| Some(invite.id))
+        .ok_or_else(|| "This channel is invite-only".to_string())
+}
+
+fn validate_not_archived(channel: &Channel) -> Result<(), String> {
+    if channel.archived {
+        Err("This channel is archived".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn add_channel_member(ctx: &ReducerContext, channel_id: u64, identity: Identity) {
+    ctx.db.channel_member().insert(ChannelMember {
+        id: 0,
+        channel_id,
+        identity,
+        joined_at: ctx.timestamp,
+        is_moderator: false,
+        nickname: None,
+    });
+}
+
+#[reducer]
+/// Channel members invoke this reducer to invite another user to the channel.
+pub fn invite_to_channel(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    target: Identity,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+    let channel = validate_channel_member(ctx, channel_id)?;
+    validate_not_archived(&channel)?;
+
+    match ctx.db.user().identity().find(target) {
+        Some(user) if user.authorized => {}
+        Some(_) => return Err("Cannot invite an unauthorized user".to_string()),
+        None => return Err("Cannot invite an unknown user".to_string()),
+    }
+    if is_channel_member(ctx, channel_id, target) {
+        return Err("Already a member of this channel".to_string());
+    }
+    let already_invited = ctx
+        .db
+        .channel_invite()
+        .invitee()
+        .filter(target)
+        .any(|invite| invite.channel_id == channel_id);
+    if already_invited {
+        return Err("This user has already been invited".to_string());
+    }
+
+    ctx.db.channel_invite().insert(ChannelInvite {
+        id: 0,
+        channel_id,
+        invitee: target,
+        inviter: ctx.sender,
+        created_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+/// Returns a pending channel invite addressed to the caller.
+fn received_channel_invite(ctx: &ReducerContext, invite_id: u64) -> Result<ChannelInvite, String> {
+    match ctx.db.channel_invite().id().find(invite_id) {
+        Some(invite) if invite.invitee == ctx.sender => Ok(invite),
+        _ => Err("No such channel invite".to_string()),
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to join a channel they were invited to.
+pub fn accept_channel_invite(ctx: &ReducerContext, invite_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let invite = received_channel_invite(ctx, invite_id)?;
+    ctx.db.channel_invite().id().delete(invite.id);
+    let Some(channel) = ctx.db.channel().id().find(invite.channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    validate_not_archived(&channel)?;
+    if !is_channel_member(ctx, invite.channel_id, ctx.sender) {
+        add_channel_member(ctx, invite.channel_id, ctx.sender);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to turn down a channel invite.
+pub fn decline_channel_invite(ctx: &ReducerContext, invite_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let invite = received_channel_invite(ctx, invite_id)?;
+    ctx.db.channel_invite().id().delete(invite.id);
+    Ok(())
+}
+
+#[reducer]
+/// Deletes channel invites that have gone unanswered for too long.
+pub fn sweep_channel_invites(
+    ctx: &ReducerContext,
+    _schedule: ChannelInviteSweepSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `sweep_channel_invites` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let stale: Vec<u64> = ctx
+        .db
+        .channel_invite()
+        .iter()
+        .filter(|invite| {
+            ctx.timestamp
+                .duration_since(invite.created_at)
+                .is_some_and(|age| age > CHANNEL_INVITE_TTL)
+        })
+        .map(|invite| invite.id)
+        .collect();
+    for id in stale {
+        ctx.db.channel_invite().id().delete(id);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to leave a channel.
+pub fn leave_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    validate_channel_member(ctx, channel_id)?;
+
+    remove_channel_member(ctx, channel_id, ctx.sender);
+    if channel_id == config(ctx).default_channel_id {
+        if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
+            ctx.db.user().identity().update(User {
+                left_default: true,
+                ..user
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Adds a newly authorized user to the default channel, unless they've left it before.
+fn join_default_channel(ctx: &ReducerContext, identity: Identity) {
+    let channel_id = config(ctx).default_channel_id;
+    let left_default = ctx
+        .db
+        .user()
+        .identity()
+        .find(identity)
+        .is_some_and(|user| user.left_default);
+    let exists = ctx.db.channel().id().find(channel_id).is_some();
+    if exists && !left_default && !is_channel_member(ctx, channel_id, identity) {
+        add_channel_member(ctx, channel_id, identity);
+    }
+}
+
+#[reducer]
+/// Admins invoke this reducer to choose which channel newly authorized users join.
+pub fn set_default_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    match ctx.db.channel().id().find(channel_id) {
+        Some(channel) if channel.invite_only => {
+            Err("The default channel must not be invite-only".to_string())
+        }
+        Some(channel) => {
+            validate_not_archived(&channel)?;
+            ctx.db.config().id().update(Config {
+                default_channel_id: channel_id,
+                ..config(ctx)
+            });
+            Ok(())
+        }
+        None => Err("Unknown channel".to_string()),
+    }
+}
+
+fn remove_channel_member(ctx: &ReducerContext, channel_id: u64, identity: Identity) {
+    if let Some(member) = channel_membership(ctx, channel_id, identity) {
+        ctx.db.channel_member().id().delete(member.id);
+    }
+}
+
+#[reducer]
+/// Channel moderators invoke this reducer to remove a member from a channel.
+/// Kicked members may join again.
+pub fn kick_member(ctx: &ReducerContext, channel_id: u64, target: Identity) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let channel = validate_channel_member(ctx, channel_id)?;
+    if !can_moderate_channel(ctx, &user, &channel) {
+        return Err("Only channel moderators may kick members".to_string());
+    }
+    if target == ctx.sender || target == channel.creator {
+        return Err("Cannot kick this member".to_string());
+    }
+    if !is_channel_member(ctx, channel_id, target) {
+        return Err("Not a member of this channel".to_string());
+    }
+
+    remove_channel_member(ctx, channel_id, target);
+    audit(ctx, "kick_member", Some(target), Some(channel_id));
+    Ok(())
+}
+
+#[reducer]
+/// Channel creators and admins invoke this reducer to make a member a channel moderator.
+pub fn promote_channel_moderator(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    target: Identity,
+) -> Result<(), String> {
+    set_channel_moderator(ctx, channel_id, target, true)
+}
+
+#[reducer]
+/// Channel creators and admins invoke this reducer to revoke a member's channel moderator status.
+pub fn demote_channel_moderator(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    target: Identity,
+) -> Result<(), String> {
+    set_channel_moderator(ctx, channel_id, target, false)
+}
+
+fn set_channel_moderator(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    target: Identity,
+    is_moderator: bool,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    if channel.creator != ctx.sender && !user.admin {
+        return Err(
+            "Only the channel creator or an admin may change channel moderators".to_string(),
+        );
+    }
+    let Some(member) = channel_membership(ctx, channel_id, target) else {
+        return Err("Not a member of this channel".to_string());
+    };
+
+    ctx.db.channel_member().id().update(ChannelMember {
+        is_moderator,
+        ..member
+    });
+    let action = if is_moderator {
+        "promote_channel_moderator"
+    } else {
+        "demote_channel_moderator"
+    };
+    audit(ctx, action, Some(target), Some(channel_id));
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to send a message to a channel they're a member of.
+pub fn send_channel_message(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    text: String,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let channel = validate_channel_member(ctx, channel_id)?;
+    validate_not_archived(&channel)?;
+    post_message(ctx, user, channel_id, MessageKind::Normal, text)?;
+    Ok(())
+}
+
+#[reducer]
+/// Channel members invoke this reducer to set how they're shown in a channel;
+/// an empty nickname clears it, so their global name shows.
+pub fn set_channel_nickname(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    nickname: String,
+) -> Result<(), String> {
+    validate_identity(ctx)?;
+    validate_channel_member(ctx, channel_id)?;
+    let member = channel_membership(ctx, channel_id, ctx.sender).ok_or("Not a channel member")?;
+
+    let nickname = if nickname.is_empty() {
+        None
+    } else {
+        let nickname = validate_name(ctx, nickname)?;
+        let normalized = normalize_name(&nickname);
+        let taken = ctx
+            .db
+            .channel_member()
+            .channel_id()
+            .filter(channel_id)
+            .any(|other| {
+                other.identity != ctx.sender
+                    && other.nickname.as_deref().map(normalize_name).as_ref() == Some(&normalized)
+            });
+        if taken {
+            return Err("Nickname already taken in this channel".to_string());
+        }
+        Some(nickname)
+    };
+
+    ctx.db
+        .channel_member()
+        .id()
+        .update(ChannelMember { nickname, ..member });
+    Ok(())
+}
+
+#[reducer]
+/// Channel moderators invoke this reducer to clear another member's channel nickname.
+pub fn clear_channel_nickname(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    target: Identity,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let channel = validate_channel_member(ctx, channel_id)?;
+    if !can_moderate_channel(ctx, &user, &channel) {
+        return Err("Only channel moderators may clear nicknames".to_string());
+    }
+    let Some(member) = channel_membership(ctx, channel_id, target) else {
+        return Err("Not a member of this channel".to_string());
+    };
+    if member.nickname.is_none() {
+        return Err("This member has no channel nickname".to_string());
+    }
+
+    ctx.db.channel_member().id().update(ChannelMember {
+        nickname: None,
+        ..member
+    });
+    audit(
+        ctx,
+        "clear_channel_nickname",
+        Some(target),
+        Some(channel_id),
+    );
+    Ok(())
+}
+
+/// Returns the channels in a category, or the uncategorized ones, in sidebar order.
+fn sibling_channels(ctx: &ReducerContext, category_id: Option<u64>) -> Vec<Channel> {
+    let mut channels: Vec<Channel> = ctx
+        .db
+        .channel()
+        .iter()
+        .filter(|channel| channel.category_id == category_id)
+        .collect();
+    channels.sort_by_key(|channel| (channel.position, channel.id));
+    channels
+}
+
+/// Stores each channel's index in `channels` as its position, keeping positions dense.
+fn store_channel_order(ctx: &ReducerContext, channels: Vec<Channel>) {
+    for (position, channel) in (0..).zip(channels) {
+        if channel.position != position {
+            ctx.db.channel().id().update(Channel {
+                position,
+                ..channel
+            });
+        }
+    }
+}
+
+#[reducer]
+/// Admins invoke this reducer to add a sidebar category, listed after the existing ones.
+pub fn create_category(ctx: &ReducerContext, name: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let name = name.trim().to_string();
+    let max_len = config(ctx).max_name_len as usize;
+    if name.is_empty() {
+        return Err("Category names must not be empty".to_string());
+    }
+    if grapheme_len(&name) > max_len {
+        return Err(format!(
+            "Category names must be at most {max_len} characters"
+        ));
+    }
+    ctx.db.category().insert(Category {
+        id: 0,
+        name,
+        position: ctx.db.category().count() as u32,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to delete a category; its channels become uncategorized.
+pub fn delete_category(ctx: &ReducerContext, category_id: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if !ctx.db.category().id().delete(category_id) {
+        return Err("Unknown category".to_string());
+    }
+    let mut uncategorized = sibling_channels(ctx, None);
+    for channel in sibling_channels(ctx, Some(category_id)) {
+        uncategorized.push(ctx.db.channel().id().update(Channel {
+            category_id: None,
+            ..channel
+        }));
+    }
+    store_channel_order(ctx, uncategorized);
+
+    let mut categories: Vec<Category> = ctx.db.category().iter().collect();
+    categories.sort_by_key(|category| (category.position, category.id));
+    for (position, category) in (0..).zip(categories) {
+        if category.position != position {
+            ctx.db.category().id().update(Category {
+                position,
+                ..category
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to move a channel to the end of a category,
+/// or of the uncategorized channels.
+pub fn set_channel_category(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    category_id: Option<u64>,
+) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    if let Some(category_id) = category_id {
+        if ctx.db.category().id().find(category_id).is_none() {
+            return Err("Unknown category".to_string());
+        }
+    }
+    if channel.category_id == category_id {
+        return Ok(());
+    }
+
+    let old_category_id = channel.category_id;
+    let mut new_siblings = sibling_channels(ctx, category_id);
+    new_siblings.push(ctx.db.channel().id().update(Channel {
+        category_id,
+        ..channel
+    }));
+    store_channel_order(ctx, new_siblings);
+    store_channel_order(ctx, sibling_channels(ctx, old_category_id));
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to move a channel within its category,
+/// shifting the channels after it along.
+pub fn reorder_channel(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    new_position: u32,
+) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    let mut siblings = sibling_channels(ctx, channel.category_id);
+    siblings.retain(|sibling| sibling.id != channel_id);
+    let index = (new_position as usize).min(siblings.len());
+    siblings.insert(index, channel);
+    store_channel_order(ctx, siblings);
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to delete a channel's entire message history.
+pub fn clear_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+    if ctx.db.channel().id().find(channel_id).is_none() {
+        return Err("Unknown channel".to_string());
+    }
+
+    let message_ids: Vec<u64> = ctx
+        .db
+        .message()
+        .channel_id()
+        .filter(channel_id)
+        .map(|message| message.id)
+        .collect();
+    for &message_id in &message_ids {
+        delete_message_cascade(ctx, message_id);
+    }
+    audit(ctx, "clear_channel", None, Some(channel_id));
+    log::info!(
+        "Cleared {} messages from channel {channel_id}",
+        message_ids.len()
+    );
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to delete every message sent by `target`, e.g. a spammer.
+pub fn delete_user_messages(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let message_ids = message_ids_sent_by(ctx.db.message().sender().filter(target), target);
+    for &message_id in &message_ids {
+        delete_message_cascade(ctx, message_id);
+    }
+    audit(ctx, "delete_user_messages", Some(target), None);
+    log::info!(
+        "Deleted {} messages from {:?}",
+        message_ids.len(),
+        target.to_hex()
+    );
+    Ok(())
+}
+
+/// The ids of the messages sent by `sender`. Callers pass the `sender` index's matches,
+/// so the check here only guards against being handed a wider scan.
+fn message_ids_sent_by(messages: impl IntoIterator<Item = Message>, sender: Identity) -> Vec<u64> {
+    messages
+        .into_iter()
+        .filter(|message| message.sender == sender)
+        .map(|message| message.id)
+        .collect()
+}
+
+#[reducer]
+/// Channel creators and admins invoke this reducer to archive a channel.
+pub fn archive_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    set_channel_archived(ctx, channel_id, true)
+}
+
+#[reducer]
+/// Channel creators and admins invoke this reducer to reopen an archived channel.
+pub fn unarchive_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    set_channel_archived(ctx, channel_id, false)
+}
+
+fn set_channel_archived(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    archived: bool,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    if channel.creator != ctx.sender && !user.admin {
+        return Err("Only the channel creator or an admin may archive a channel".to_string());
+    }
+    if archived && channel_id == config(ctx).default_channel_id {
+        return Err("The default channel cannot be archived".to_string());
+    }
+    if channel.archived == archived {
+        return Ok(());
+    }
+
+    ctx.db.channel().id().update(Channel {
+        archived,
+        ..channel
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Channel creators and admins invoke this reducer to delete a channel along with its
+/// members, invites and messages.
+pub fn delete_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    check_channel_owner(&channel, &user, "delete")?;
+    check_channel_deletable(channel_id, config(ctx).default_channel_id)?;
+
+    let message_ids: Vec<u64> = ctx
+        .db
+        .message()
+        .channel_id()
+        .filter(channel_id)
+        .map(|message| message.id)
+        .collect();
+    for &message_id in &message_ids {
+        delete_message_cascade(ctx, message_id);
+    }
+    ctx.db.channel_member().channel_id().delete(channel_id);
+    ctx.db.channel_invite().channel_id().delete(channel_id);
+    ctx.db.pending_message().channel_id().delete(channel_id);
+    let typing: Vec<u64> = ctx
+        .db
+        .typing_indicator()
+        .iter()
+        .filter(|indicator| indicator.channel_id == channel_id)
+        .map(|indicator| indicator.id)
+        .collect();
+    for id in typing {
+        ctx.db.typing_indicator().id().delete(id);
+    }
+    let markers: Vec<u64> = ctx
+        .db
+        .read_marker()
+        .iter()
+        .filter(|marker| marker.channel_id == channel_id)
+        .map(|marker| marker.id)
+        .collect();
+    for id in markers {
+        ctx.db.read_marker().id().delete(id);
+    }
+    // Search results and loaded pages copy the channel's messages and roster
+    let search_results: Vec<u64> = ctx
+        .db
+        .search_result()
+        .iter()
+        .filter(|result| result.channel_id == channel_id)
+        .map(|result| result.id)
+        .collect();
+    for id in search_results {
+        ctx.db.search_result().id().delete(id);
+    }
+    let loaded_messages: Vec<u64> = ctx
+        .db
+        .loaded_message()
+        .iter()
+        .filter(|loaded| loaded.channel_id == channel_id)
+        .map(|loaded| loaded.id)
+        .collect();
+    for id in loaded_messages {
+        ctx.db.loaded_message().id().delete(id);
+    }
+    let loaded_members: Vec<u64> = ctx
+        .db
+        .loaded_channel_member()
+        .iter()
+        .filter(|loaded| loaded.channel_id == channel_id)
+        .map(|loaded| loaded.id)
+        .collect();
+    for id in loaded_members {
+        ctx.db.loaded_channel_member().id().delete(id);
+    }
+    let unread_counts: Vec<u64> = ctx
+        .db
+        .unread_count()
+        .iter()
+        .filter(|unread| unread.channel_id == channel_id)
+        .map(|unread| unread.id)
+        .collect();
+    for id in unread_counts {
+        ctx.db.unread_count().id().delete(id);
+    }
+    ctx.db.channel().id().delete(channel_id);
+    store_channel_order(ctx, sibling_channels(ctx, channel.category_id));
+
+    audit(ctx, "delete_channel", None, Some(channel_id));
+    log::info!(
+        "Deleted channel {channel_id} with {} messages",
+        message_ids.len()
+    );
+    Ok(())
+}
+
+#[reducer]
+/// Channel creators and admins invoke this reducer to rename a channel.
+pub fn rename_channel(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    new_name: String,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let Some(channel) = ctx.db.channel().id().find(channel_id) else {
+        return Err("Unknown channel".to_string());
+    };
+    check_channel_owner(&channel, &user, "rename")?;
+    if new_name.trim() == channel.name {
+        return Ok(());
+    }
+
+    let name = validate_channel_name(ctx, new_name)?;
+    ctx.db.channel().id().update(Channel { name, ..channel });
+    audit(ctx, "rename_channel", None, Some(channel_id));
+    Ok(())
+}
+
+#[reducer]
+/// Channel moderators invoke this reducer to set a channel's topic.
+/// An empty topic clears it.
+pub fn set_channel_topic(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    topic: String,
+) -> Result<(), String> {
+    let user = validate_identity(ctx)?;
+    let channel = validate_channel_member(ctx, channel_id)?;
+    if !can_moderate_channel(ctx, &user, &channel) {
+        return Err("Only channel moderators may set the topic".to_string());
+    }
+
+    let topic = topic.trim().to_string();
+    if grapheme_len(&topic) > MAX_TOPIC_LEN {
+        return Err(format!("Topics must be at most {MAX_TOPIC_LEN} characters"));
+    }
+    if has_disallowed_control_chars(&topic) {
+        return Err("Topics must not contain control characters".to_string());
+    }
+
+    let announcement = if topic.is_empty() {
+        format!("{} cleared the topic", display_name(&user))
+    } else {
+        format!("{} changed the topic to {}", display_name(&user), topic)
+    };
+    ctx.db.channel().id().update(Channel {
+        topic: (!topic.is_empty()).then_some(topic),
+        ..channel
+    });
+    post_system_message(ctx, channel_id, announcement);
+    Ok(())
+}
+
+/// Orders a pair of identities the way `friendship` stores them.
+fn friendship_pair(x: Identity, y: Identity) -> (Identity, Identity) {
+    if x < y {
+        (x, y)
+    } else {
+        (y, x)
+    }
+}
+
+fn are_friends(ctx: &ReducerContext, x: Identity, y: Identity) -> bool {
+    let (a, b) = friendship_pair(x, y);
+    ctx.db
+        .friendship()
+        .a()
+        .filter(a)
+        .any(|friendship| friendship.b == b)
+}
+
+/// Finds a pending request between two users, in either direction.
+fn pending_friend_request(ctx: &ReducerContext, x: Identity, y: Identity) -> Option<FriendRequest> {
+    ctx.db
+        .friend_request()
+        .from()
+        .filter(x)
+        .find(|request| request.to == y)
+        .or_else(|| {
+            ctx.db
+                .friend_request()
+                .from()
+                .filter(y)
+                .find(|request| request.to == x)
+        })
+}
+
+#[reducer]
+/// Clients invoke this reducer to ask another user to be their friend.
+pub fn send_friend_request(ctx: &ReducerContext, to: Identity) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    if to == ctx.sender {
+        return Err("Cannot send a friend request to yourself".to_string());
+    }
+    if ctx.db.user().identity().find(to).is_none() {
+        return Err("Cannot send a friend request to an unknown user".to_string());
+    }
+    if has_blocked(ctx, to, ctx.sender) {
+        return Err("This user is not accepting friend requests from you".to_string());
+    }
+    if has_blocked(ctx, ctx.sender, to) {
+        return Err("Unblock this user before sending them a friend request".to_string());
+    }
+    if are_friends(ctx, ctx.sender, to) {
+        return Err("Already friends with this user".to_string());
+    }
+    if pending_friend_request(ctx, ctx.sender, to).is_some() {
+        return Err("A friend request with this user is already pending".to_string());
+    }
+
+    ctx.db.friend_request().insert(FriendRequest {
+        id: 0,
+        from: ctx.sender,
+        to,
+        created_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+/// Returns a pending friend request addressed to the caller.
+fn received_friend_request(ctx: &ReducerContext, request_id: u64) -> Result<FriendRequest, String> {
+    match ctx.db.friend_request().id().find(request_id) {
+        Some(request) if request.to == ctx.sender => Ok(request),
+        _ => Err("No such friend request".to_string()),
+    }
+}
+
+#[reducer]
+/// Clients invoke this reducer to accept a friend request sent to them.
+pub fn accept_friend_request(ctx: &ReducerContext, request_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let request = received_friend_request(ctx, request_id)?;
+    ctx.db.friend_request().id().delete(request.id);
+    let (a, b) = friendship_pair(request.from, request.to);
+    ctx.db.friendship().insert(Friendship {
+        id: 0,
+        a,
+        b,
+        since: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to decline a friend request sent to them.
+pub fn decline_friend_request(ctx: &ReducerContext, request_id: u64) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let request = received_friend_request(ctx, request_id)?;
+    ctx.db.friend_request().id().delete(request.id);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to end a friendship.
+pub fn remove_friend(ctx: &ReducerContext, friend: Identity) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    if !remove_friendship(ctx, ctx.sender, friend) {
+        return Err("Not friends with this user".to_string());
+    }
+    Ok(())
+}
+
+/// Deletes the friendship between two users, returning whether there was one.
+fn remove_friendship(ctx: &ReducerContext, x: Identity, y: Identity) -> bool {
+    let (a, b) = friendship_pair(x, y);
+    let friendship = ctx
+        .db
+        .friendship()
+        .a()
+        .filter(a)
+        .find(|friendship| friendship.b == b);
+    match friendship {
+        Some(friendship) => ctx.db.friendship().id().delete(friendship.id),
+        None => false,
+    }
+}
+
+fn has_blocked(ctx: &ReducerContext, blocker: Identity, blocked: Identity) -> bool {
+    ctx.db
+        .block()
+        .blocker()
+        .filter(blocker)
+        .any(|block| block.blocked == blocked)
+}
+
+#[reducer]
+/// Clients invoke this reducer to block a user, ending any friendship or pending request with them.
+pub fn block_user(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    if target == ctx.sender {
+        return Err("Cannot block yourself".to_string());
+    }
+    if ctx.db.user().identity().find(target).is_none() {
+        return Err("Cannot block an unknown user".to_string());
+    }
+    if has_blocked(ctx, ctx.sender, target) {
+        return Err("Already blocked this user".to_string());
+    }
+
+    remove_friendship(ctx, ctx.sender, target);
+    while let Some(request) = pending_friend_request(ctx, ctx.sender, target) {
+        ctx.db.friend_request().id().delete(request.id);
+    }
+    ctx.db.block().insert(Block {
+        id: 0,
+        blocker: ctx.sender,
+        blocked: target,
+        created_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to unblock a user they blocked.
+pub fn unblock_user(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    validate_identity(ctx)?;
+
+    let block = ctx
+        .db
+        .block()
+        .blocker()
+        .filter(ctx.sender)
+        .find(|block| block.blocked == target);
+    let Some(block) = block else {
+        return Err("This user is not blocked".to_string());
+    };
+    ctx.db.block().id().delete(block.id);
+    Ok(())
+}
+
+/// Splits a typing scope into the `(channel_id, conversation_id)` pair stored on the row.
+fn typing_scope_ids(scope: TypingScope) -> (u64, u64) {
+    match scope {
+        TypingScope::Global => (GLOBAL_CHANNEL_ID, 0),
+        TypingScope::Channel(channel_id) => (channel_id, 0),
+        TypingScope::Conversation(conversation_id) => (GLOBAL_CHANNEL_ID, conversation_id),
+    }
+}
+
+fn find_typing_indicator(ctx: &ReducerContext, scope: TypingScope) -> Option<TypingIndicator> {
+    let (channel_id, conversation_id) = typing_scope_ids(scope);
+    ctx.db
+        .typing_indicator()
+        .identity()
+        .filter(ctx.sender)
+        .find(|typing| typing.channel_id == channel_id && typing.conversation_id == conversation_id)
+}
+
+#[reducer]
+/// Clients invoke this reducer while the user is typing, in the room, a channel or a conversation.
+pub fn start_typing(ctx: &ReducerContext, scope: TypingScope) -> Result<(), String> {
+    validate_identity(ctx)?;
+    match scope {
+        TypingScope::Global => {}
+        TypingScope::Channel(channel_id) => {
+            validate_channel_member(ctx, channel_id)?;
+        }
+        TypingScope::Conversation(conversation_id) => {
+            validate_conversation_member(ctx, conversation_id)?;
+        }
+    }
+
+    let expires_at = ctx
+        .timestamp
+        .checked_add_duration(TYPING_TIMEOUT)
+        .unwrap_or(ctx.timestamp);
+    match find_typing_indicator(ctx, scope) {
+        Some(typing) => {
+            ctx.db.typing_indicator().id().update(TypingIndicator {
+                expires_at,
+                ..typing
+            });
+        }
+        None => {
+            let (channel_id, conversation_id) = typing_scope_ids(scope);
+            ctx.db.typing_indicator().insert(TypingIndicator {
+                id: 0,
+                identity: ctx.sender,
+                channel_id,
+                conversation_id,
+                expires_at,
+                dummy_join: true,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer when the user stops typing.
+pub fn stop_typing(ctx: &ReducerContext, scope: TypingScope) {
+    if let Some(typing) = find_typing_indicator(ctx, scope) {
+        ctx.db.typing_indicator().id().delete(typing.id);
+    }
+}
+
+#[reducer]
+/// Removes typing indicators that weren't refreshed in time, whatever their scope.
+pub fn expire_typing_indicators(
+    ctx: &ReducerContext,
+    _schedule: TypingSweepSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `expire_typing_indicators` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let expired: Vec<u64> = ctx
+        .db
+        .typing_indicator()
+        .iter()
+        .filter(|typing| typing.expires_at <= ctx.timestamp)
+        .map(|typing| typing.id)
+        .collect();
+    for id in expired {
+        ctx.db.typing_indicator().id().delete(id);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Replaces the leaderboard with the current top senders from `user_stats`.
+pub fn rebuild_leaderboard(
+    ctx: &ReducerContext,
+    _schedule: LeaderboardSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `rebuild_leaderboard` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let entries = leaderboard_entries(
+        ctx.db
+            .user_stats()
+            .iter()
+            .map(|stats| (stats.identity, stats.messages_sent)),
+    );
+
+    let old_ranks: Vec<u32> = ctx
+        .db
+        .leaderboard()
+        .iter()
+        .map(|entry| entry.rank)
+        .collect();
+    for rank in old_ranks {
+        ctx.db.leaderboard().rank().delete(rank);
+    }
+    for entry in entries {
+        ctx.db.leaderboard().insert(entry);
+    }
+    Ok(())
+}
+
+/// Ranks the top `LEADERBOARD_SIZE` senders by message count, from 1.
+fn leaderboard_entries(counts: impl IntoIterator<Item = (Identity, u64)>) -> Vec<Leaderboard> {
+    let mut counts: Vec<(Identity, u64)> = counts.into_iter().collect();
+    // Break ties by identity so the order is stable between rebuilds.
+    counts.sort_by_key(|&(identity, count)| (std::cmp::Reverse(count), identity));
+    (1..)
+        .zip(counts.into_iter().take(LEADERBOARD_SIZE))
+        .map(|(rank, (identity, count))| Leaderboard {
+            rank,
+            identity,
+            count,
+        })
+        .collect()
+}
+
+#[reducer]
+/// Admins invoke this reducer to let a user take part in the chat.
+pub fn authorize_user(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let Some(user) = ctx.db.user().identity().find(target) else {
+        return Err("Cannot authorize unknown user".to_string());
+    };
+    if user.authorized {
+        return Ok(());
+    }
+    ctx.db.user().identity().update(User {
+        authorized: true,
+        ..user
+    });
+    join_default_channel(ctx, target);
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to authorize an identity as soon as it first connects.
+pub fn allowlist_add(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if ctx.db.allowlist().identity().find(identity).is_none() {
+        ctx.db.allowlist().insert(Allowlist {
+            identity,
+            added_by: ctx.sender,
+            added_at: ctx.timestamp,
+            dummy_join: true,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to remove an identity from the allowlist.
+/// Users who were already authorized stay authorized.
+pub fn allowlist_remove(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if ctx.db.allowlist().identity().delete(identity) {
+        Ok(())
+    } else {
+        Err("Identity is not on the allowlist".to_string())
+    }
+}
+
+#[reducer]
+/// Admins invoke this reducer to require moderator approval of new users' first messages.
+pub fn set_moderate_first_message(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    ctx.db.config().id().update(Config {
+        moderate_first_message: enabled,
+        ..config(ctx)
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to set how old an account must be before it can send messages.
+pub fn set_min_account_age(ctx: &ReducerContext, secs: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    ctx.db.config().id().update(Config {
+        min_account_age_secs: secs,
+        ..config(ctx)
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to allow or forbid links in regular users' messages.
+pub fn set_allow_links(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    ctx.db.config().id().update(Config {
+        allow_links: enabled,
+        ..config(ctx)
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to let unauthorized users read the room as a whole.
+pub fn set_guest_read_access(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    ctx.db.config().id().update(Config {
+        guest_read_access: enabled,
+        ..config(ctx)
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to change the message new users are welcomed with.
+pub fn set_welcome_message(ctx: &ReducerContext, text: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if text.trim().is_empty() {
+        return Err("Welcome message must not be empty".to_string());
+    }
+    if grapheme_len(&text) > MAX_WELCOME_MESSAGE_LEN {
+        return Err(format!(
+            "Welcome message must be at most {MAX_WELCOME_MESSAGE_LEN} characters"
+        ));
+    }
+    if has_disallowed_control_chars(&text) {
+        return Err("Welcome message must not contain control characters".to_string());
+    }
+
+    ctx.db.config().id().update(Config {
+        welcome_message: text,
+        ..config(ctx)
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to snapshot every message into an `export_blob` row as JSON.
+pub fn export_messages(ctx: &ReducerContext) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let messages: Vec<Message> = ctx.db.message().iter().collect();
+    let json = export_json(&messages)?;
+    ctx.db.export_blob().insert(ExportBlob {
+        id: 0,
+        json,
+        created_at: ctx.timestamp,
+        dummy_join: true,
+    });
+    log::info!("Exported {} messages", messages.len());
+    Ok(())
+}
+
+/// Serializes messages into the JSON `export_messages` stores.
+fn export_json(messages: &[Message]) -> Result<String, String> {
+    let messages: Vec<ExportedMessage> = messages.iter().map(ExportedMessage::from).collect();
+    serde_json::to_string(&messages).map_err(|e| e.to_string())
+}
+
+/// Erases a user's messages from an export blob's JSON the way `erase_user` erases them
+/// from `message`, returning the new JSON and how many messages changed.
+/// Returns `None` if the JSON can't be parsed, so the caller can drop the blob instead.
+fn scrub_export(json: &str, identity: Identity) -> Option<(String, u64)> {
+    let mut messages: Vec<ExportedMessage> = serde_json::from_str(json).ok()?;
+    let hex = identity.to_hex().to_string();
+    let mut scrubbed = 0;
+    for message in &mut messages {
+        if message.sender == hex {
+            message.sender = DELETED_USER.to_hex().to_string();
+            message.text = String::new();
+            scrubbed += 1;
+        }
+    }
+    Some((serde_json::to_string(&messages).ok()?, scrubbed))
+}
+
+/// Parses a hex identity, explaining what's wrong with it if it's invalid.
+fn parse_identity_hex(hex: &str) -> Result<Identity, String> {
+    if hex.len() != 64 {
+        return Err(format!("expected 64 hex chars, got {}", hex.len()));
+    }
+    Identity::from_hex(hex).map_err(|err| format!("not valid hex: {err}"))
+}
+
+#[reducer]
+/// Admins invoke this reducer to restore messages from `export_messages` JSON.
+/// Imported messages get fresh ids; messages with empty text are skipped.
+pub fn import_messages(ctx: &ReducerContext, json: String) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let mut imported = 0;
+    for message in parse_import(&json)? {
+        if message.channel_id != GLOBAL_CHANNEL_ID
+            && ctx.db.channel().id().find(message.channel_id).is_none()
+        {
+            return Err(format!("Unknown channel in message {}", message.id));
+        }
+        ctx.db.message().insert(Message {
+            id: 0,
+            seq: next_seq(ctx, message.channel_id),
+            ..message
+        });
+        imported += 1;
+    }
+    adjust_room_stats(ctx, 0, 0, imported);
+    log::info!("Imported {imported} messages");
+    Ok(())
+}
+
+/// Parses `export_messages` JSON into messages, skipping any with empty text.
+/// Each message keeps its exported id, for error messages, and has no `seq` yet.
+fn parse_import(json: &str) -> Result<Vec<Message>, String> {
+    let messages: Vec<ExportedMessage> =
+        serde_json::from_str(json).map_err(|e| format!("Invalid import JSON: {e}"))?;
+    messages
+        .into_iter()
+        .filter(|message| !message.text.trim().is_empty())
+        .map(|message| {
+            let sender = parse_identity_hex(&message.sender)
+                .map_err(|err| format!("Invalid sender in message {}: {err}", message.id))?;
+            Ok(Message {
+                id: message.id,
+                channel_id: message.channel_id,
+                sender,
+                sent: Timestamp::from_micros_since_unix_epoch(message.sent),
+                text: message.text,
+                edited_at: message
+                    .edited_at
+                    .map(Timestamp::from_micros_since_unix_epoch),
+                hidden: message.hidden,
+                expires_at: message
+                    .expires_at
+                    .map(Timestamp::from_micros_since_unix_epoch),
+                forwarded_from: None,
+                forwarded_message_id: None,
+                seq: 0,
+                kind: MessageKind::Normal,
+                dummy_join: true,
+            })
+        })
+        .collect()
+}
+
+#[reducer]
+/// Admins invoke this reducer to define a new kind of badge.
+pub fn create_badge(
+    ctx: &ReducerContext,
+    name: String,
+    icon: String,
+    description: String,
+) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let name = name.trim().to_string();
+    if name.is_empty() || grapheme_len(&name) > MAX_BADGE_NAME_LEN {
+        return Err(format!(
+            "Badge names must be between 1 and {MAX_BADGE_NAME_LEN} characters"
+        ));
+    }
+    if grapheme_len(&icon) > MAX_BADGE_TEXT_LEN || grapheme_len(&description) > MAX_BADGE_TEXT_LEN {
+        return Err(format!(
+            "Badge icons and descriptions must be at most {MAX_BADGE_TEXT_LEN} characters"
+        ));
+    }
+    if [&name, &icon, &description]
+        .iter()
+        .any(|text| has_disallowed_control_chars(text))
+    {
+        return Err("Badges must not contain control characters".to_string());
+    }
+    if ctx.db.badge().name().find(&name).is_some() {
+        return Err("A badge with that name already exists".to_string());
+    }
+
+    ctx.db.badge().insert(Badge {
+        id: 0,
+        name,
+        icon,
+        description,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to delete a badge, taking it away from everyone who holds it.
+pub fn delete_badge(ctx: &ReducerContext, badge_id: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if !ctx.db.badge().id().delete(badge_id) {
+        return Err("Unknown badge".to_string());
+    }
+    ctx.db.user_badge().badge_id().delete(badge_id);
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to give a user a badge.
+pub fn grant_badge(ctx: &ReducerContext, target: Identity, badge_id: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if ctx.db.badge().id().find(badge_id).is_none() {
+        return Err("Unknown badge".to_string());
+    }
+    if ctx.db.user().identity().find(target).is_none() {
+        return Err("Unknown user".to_string());
+    }
+    if find_user_badge(ctx, target, badge_id).is_some() {
+        return Err("User already has this badge".to_string());
+    }
+
+    ctx.db.user_badge().insert(UserBadge {
+        id: 0,
+        identity: target,
+        badge_id,
+        granted_at: ctx.timestamp,
+        granted_by: ctx.sender,
+    });
+    audit(ctx, "grant_badge", Some(target), None);
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to take a badge away from a user.
+pub fn revoke_badge(ctx: &ReducerContext, target: Identity, badge_id: u64) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let Some(user_badge) = find_user_badge(ctx, target, badge_id) else {
+        return Err("User does not have this badge".to_string());
+    };
+    ctx.db.user_badge().id().delete(user_badge.id);
+    audit(ctx, "revoke_badge", Some(target), None);
+    Ok(())
+}
+
+fn find_user_badge(ctx: &ReducerContext, identity: Identity, badge_id: u64) -> Option<UserBadge> {
+    ctx.db
+        .user_badge()
+        .identity()
+        .filter(identity)
+        .find(|user_badge| user_badge.badge_id == badge_id)
+}
+
+#[reducer]
+/// Admins invoke this reducer to change another user's role.
+pub fn set_role(ctx: &ReducerContext, target: Identity, role: Role) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    if target == ctx.sender {
+        return Err("Admins cannot change their own role".to_string());
+    }
+    if let Some(user) = ctx.db.user().identity().find(target) {
+        ctx.db.user().identity().update(User {
+            moderator: role == Role::Moderator,
+            admin: role == Role::Admin,
+            ..user
+        });
+        Ok(())
+    } else {
+        Err("Cannot set role for unknown user".to_string())
+    }
+}
+
+#[reducer(client_connected)]
+// Called when a client connects to a SpacetimeDB database server
+pub fn client_connected(ctx: &ReducerContext) {
+    let user = ensure_user_row(ctx);
+    if !user.authorized {
+        log::warn!(
+            "Unauthorized user connected: {:?} (account age {}s)",
+            user.identity.to_hex(),
+            account_age_secs(&user, ctx.timestamp)
+        );
+    }
+
+    // A session still open here lost its disconnect, e.g. to a host restart.
+    for session in open_sessions(ctx, ctx.sender) {
+        ctx.db.session().id().update(Session {
+            disconnected_at: Some(ctx.timestamp),
+            ..session
+        });
+    }
+    ctx.db.session().insert(Session {
+        id: 0,
+        identity: ctx.sender,
+        connected_at: ctx.timestamp,
+        disconnected_at: None,
+        dummy_join: true,
+    });
+}
+
+/// How long ago the user's row was created, in whole seconds.
+fn account_age_secs(user: &User, now: Timestamp) -> u64 {
+    now.duration_since(user.first_seen)
+        .map_or(0, |age| age.as_secs())
+}
+
+fn open_sessions(ctx: &ReducerContext, identity: Identity) -> Vec<Session> {
+    ctx.db
+        .session()
+        .identity()
+        .filter(identity)
+        .filter(|session| session.disconnected_at.is_none())
+        .collect()
+}
+
+#[reducer]
+/// Deletes sessions that began more than `SESSION_RETENTION` ago.
+pub fn trim_sessions(ctx: &ReducerContext, _schedule: SessionTrimSchedule) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Reducer `trim_sessions` may only be invoked by the scheduler".to_string());
+    }
+
+    let expired: Vec<u64> = ctx
+        .db
+        .session()
+        .iter()
+        .filter(|session| {
+            ctx.timestamp
+                .duration_since(session.connected_at)
+                .is_some_and(|age| age > SESSION_RETENTION)
+        })
+        .map(|session| session.id)
+        .collect();
+    for id in expired {
+        ctx.db.session().id().delete(id);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to make sure their `User` row exists,
+/// e.g. if they suspect the connect event was missed.
+pub fn ensure_user(ctx: &ReducerContext) {
+    ensure_user_row(ctx);
+}
+
+/// Like `validate_identity`, but creates the caller's `User` row if their connect event was
+/// missed, e.g. because a reducer call raced it.
+///
+/// The new row is a guest's, and is the one exception to the authorization check: the caller
+/// may still set a name on it, which is then in place once they're authorized. Rows that
+/// already existed are left untouched and must be authorized as usual.
+fn validate_name_setter(ctx: &ReducerContext) -> Result<User, String> {
+    match ctx.db.user().identity().find(ctx.sender) {
+        Some(user) => check_may_set_name(user, false),
+        None => {
+            log::warn!("Creating missing user row for {:?}", ctx.sender.to_hex());
+            check_may_set_name(ensure_user_row(ctx), true)
+        }
+    }
+}
+
+/// Lets authorized users set their names, and guests whose row was `just_created`.
+fn check_may_set_name(user: User, just_created: bool) -> Result<User, String> {
+    if user.authorized || just_created {
+        Ok(user)
+    } else {
+        Err("Unauthorized user attempted to perform an action".to_string())
+    }
+}
+
+/// Marks the caller online, creating their `User` row as an unauthorized guest if needed.
+fn ensure_user_row(ctx: &ReducerContext) -> User {
+    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
+        // If this is a returning user, i.e. we already have a `User` with this `Identity`,
+        // set `online: true`, but leave `name` and `identity` unchanged.
+        adjust_room_stats(ctx, online_delta(user.online, true), 0, 0);
+        ctx.db
+            .user()
+            .identity()
+            .update(mark_online(user, ctx.timestamp))
+    } else {
+        // If this is a new user, create a `User` row for the `Identity`,
+        // which is online and goes by a generated nickname until they pick a name.
+        let allowlisted = ctx.db.allowlist().identity().find(ctx.sender).is_some();
+        let user = insert_new_user(ctx, ctx.sender, allowlisted, true);
+        if allowlisted {
+            log::info!(
+                "Auto-authorized allowlisted user: {:?}",
+                ctx.sender.to_hex()
+            );
+            join_default_channel(ctx, ctx.sender);
+        }
+        user
+    }
+}
+
+/// Creates a user who goes by a generated nickname until they pick a name,
+/// along with their default preferences and welcome message.
+fn insert_new_user(
+    ctx: &ReducerContext,
+    identity: Identity,
+    authorized: bool,
+    online: bool,
+) -> User {
+    let nickname = generate_nickname(ctx, identity);
+    let user = ctx.db.user().insert(new_user(
+        identity,
+        nickname,
+        authorized,
+        online,
+        ctx.timestamp,
+    ));
+    adjust_room_stats(ctx, online as i64, 1, 0);
+    ctx.db
+        .notification_prefs()
+        .insert(NotificationPrefs::defaults(identity));
+    ctx.db.welcome().insert(Welcome {
+        identity,
+        text: config(ctx).welcome_message,
+        sent: ctx.timestamp,
+    });
+    user
+}
+
+/// A user row as first created, with the user's default color and no name of their own.
+fn new_user(
+    identity: Identity,
+    nickname: String,
+    authorized: bool,
+    online: bool,
+    now: Timestamp,
+) -> User {
+    User {
+        username: None,
+        username_normalized: None,
+        nickname: Some(nickname),
+        color: Some(default_color(identity)),
+        color_changed_at: None,
+        name_changed_at: None,
+        timezone: None,
+        locale: None,
+        avatar_url: None,
+        identity,
+        online,
+        first_seen: now,
+        last_seen: now,
+        pending_offline_at: None,
+        streak_days: 0,
+        last_active_day: None,
+        byte_window_start: None,
+        bytes_in_window: 0,
+        violation_count: 0,
+        violation_window_start: None,
+        authorized,
+        left_default: false,
+        trusted: false,
+        dnd: false,
+        moderator: false,
+        admin: false,
+        dummy_join: true,
+    }
+}
+
+/// A returning user's row, marked online as of `now` with their name and roles unchanged.
+fn mark_online(user: User, now: Timestamp) -> User {
+    User {
+        online: true,
+        last_seen: now,
+        pending_offline_at: None,
+        ..user
+    }
+}
+
+#[reducer]
+/// The publisher invokes this reducer once after publishing to choose the first admins,
+/// who also receive the Founder badge. Anyone may invoke it if there are no admins yet.
+pub fn setup_admins(ctx: &ReducerContext, admins: Vec<Identity>) -> Result<(), String> {
+    let config = config(ctx);
+    let has_admins = ctx.db.user().iter().any(|user| user.admin);
+    let admins = check_admin_setup(&config, ctx.sender, has_admins, admins)?;
+
+    let founder = ctx.db.badge().name().find("Founder".to_string());
+    for &identity in &admins {
+        let user = match ctx.db.user().identity().find(identity) {
+            Some(user) => user,
+            None => insert_new_user(ctx, identity, true, false),
+        };
+        ctx.db.user().identity().update(User {
+            authorized: true,
+            admin: true,
+            ..user
+        });
+        join_default_channel(ctx, identity);
+        if let Some(founder) = &founder {
+            ctx.db.user_badge().insert(UserBadge {
+                id: 0,
+                identity,
+                badge_id: founder.id,
+                granted_at: ctx.timestamp,
+                granted_by: ctx.identity(),
+            });
+        }
+        audit(ctx, "setup_admin", Some(identity), None);
+    }
+    ctx.db.config().id().update(Config {
+        setup_complete: true,
+        ..config
+    });
+    log::info!("Set up {} admins", admins.len());
+    Ok(())
+}
+
+/// Checks that `caller` may run `setup_admins` with the given admins, returning them in order
+/// with duplicates removed.
+fn check_admin_setup(
+    config: &Config,
+    caller: Identity,
+    has_admins: bool,
+    admins: Vec<Identity>,
+) -> Result<Vec<Identity>, String> {
+    if config.setup_complete {
+        return Err("setup already complete".to_string());
+    }
+    let may_set_up = caller == config.publisher || !has_admins;
+    if !may_set_up {
+        return Err("Only the publisher may set up admins".to_string());
+    }
+    if admins.is_empty() {
+        return Err("At least one admin is required".to_string());
+    }
+    let mut unique = Vec::new();
+    for identity in admins {
+        if !unique.contains(&identity) {
+            unique.push(identity);
+        }
+    }
+    Ok(unique)
+}
+
+#[reducer]
+/// Clients invoke this reducer to delete their account and everything tied to it.
+/// Their messages are either deleted or kept anonymously, per `anonymize_deleted_messages`.
+/// Connecting again creates a fresh, unauthorized account.
+pub fn delete_my_account(ctx: &ReducerContext) -> Result<(), String> {
+    let Some(user) = ctx.db.user().identity().find(ctx.sender) else {
+        return Err("Validation failed: Unknown user".to_string());
+    };
+    if user.admin {
+        return Err("Admins cannot delete their own account".to_string());
+    }
+
+    let fate = if config(ctx).anonymize_deleted_messages {
+        MessageFate::Anonymize
+    } else {
+        MessageFate::Delete
+    };
+    remove_account(ctx, ctx.sender, fate);
+    log::info!("Deleted account {:?}", ctx.sender.to_hex());
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to erase every trace of a user: their account is removed,
+/// their messages are blanked and attributed to `DELETED_USER`, and every other reference
+/// to them is removed or replaced with `DELETED_USER`.
+pub fn erase_user(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    validate_admin(ctx)?;
+
+    let Some(user) = ctx.db.user().identity().find(target) else {
+        return Err("Unknown user".to_string());
+    };
+    if user.admin && ctx.db.user().iter().filter(|user| user.admin).count() <= 1 {
+        return Err("Cannot erase the last admin".to_string());
+    }
+
+    let affected = remove_account(ctx, target, MessageFate::Erase) + scrub_references(ctx, target);
+    audit(ctx, "erase_user", None, None);
+    log::info!("Erased a user, affecting {affected} rows");
+    Ok(())
+}
+
+/// What `remove_account` does with the removed user's messages
+enum MessageFate {
+    /// Keep them, attributed to `DELETED_USER`
+    Anonymize,
+    /// Delete them along with their reactions and attachments
+    Delete,
+    /// Keep them attributed to `DELETED_USER`, with their text blanked
+    Erase,
+}
+
+/// Removes a user's account and the rows they own, returning how many rows were affected.
+fn remove_account(ctx: &ReducerContext, identity: Identity, fate: MessageFate) -> u64 {
+    let mut affected = 0;
+
+    let messages: Vec<Message> = ctx.db.message().sender().filter(identity).collect();
+    affected += messages.len() as u64;
+    for message in messages {
+        match fate {
+            MessageFate::Anonymize => {
+                ctx.db.message().id().update(Message {
+                    sender: DELETED_USER,
+                    ..message
+                });
+            }
+            MessageFate::Delete => delete_message_cascade(ctx, message.id),
+            MessageFate::Erase => {
+                affected += delete_message_copies(ctx, message.id);
+                ctx.db
+                    .message()
+                    .id()
+                    .update(erase_message(message, identity));
+            }
+        }
+    }
+    // Forwards by other users no longer credit the removed user. They keep their text
+    // unless the user is being erased.
+    let forwards: Vec<Message> = ctx
+        .db
+        .message()
+        .iter()
+        .filter(|message| message.forwarded_from == Some(identity))
+        .collect();
+    affected += forwards.len() as u64;
+    for message in forwards {
+        let message = match fate {
+            MessageFate::Erase => {
+                affected += delete_message_copies(ctx, message.id);
+                erase_message(message, identity)
+            }
+            _ => Message {
+                forwarded_from: Some(DELETED_USER),
+                ..message
+            },
+        };
+        ctx.db.message().id().update(message);
+    }
+    let conversation_messages: Vec<ConversationMessage> = ctx
+        .db
+        .conversation_message()
+        .iter()
+        .filter(|message| message.sender == identity)
+        .collect();
+    affected += conversation_messages.len() as u64;
+    for message in conversation_messages {
+        match fate {
+            MessageFate::Anonymize => {
+                ctx.db
+                    .conversation_message()
+                    .id()
+                    .update(ConversationMessage {
+                        sender: DELETED_USER,
+                        ..message
+                    });
+            }
+            MessageFate::Delete => {
+                ctx.db.conversation_message().id().delete(message.id);
+            }
+            MessageFate::Erase => {
+                ctx.db
+                    .conversation_message()
+                    .id()
+                    .update(erase_conversation_message(message, identity));
+            }
+        }
+    }
+
+    let reactions: Vec<Reaction> = ctx
+        .db
+        .reaction()
+        .iter()
+        .filter(|reaction| reaction.identity == identity)
+        .collect();
+    affected += reactions.len() as u64;
+    for reaction in reactions {
+        delete_reaction(ctx, reaction);
+    }
+
+    let votes: Vec<u64> = ctx
+        .db
+        .poll_vote()
+        .iter()
+        .filter(|vote| vote.voter == identity)
+        .map(|vote| vote.id)
+        .collect();
+    affected += votes.len() as u64;
+    for id in votes {
+        ctx.db.poll_vote().id().delete(id);
+    }
+
+    let memberships: Vec<ConversationMember> = ctx
+        .db
+        .conversation_member()
+        .identity()
+        .filter(identity)
+        .collect();
+    affected += memberships.len() as u64;
+    for member in memberships {
+        ctx.db.conversation_member().id().delete(member.id);
+        // What's left is no longer a conversation between this pair
+        ctx.db
+            .direct_conversation()
+            .conversation_id()
+            .delete(member.conversation_id);
+        if let Some(conversation) = ctx.db.conversation().id().find(member.conversation_id) {
+            let participants = conversation
+                .participants
+                .iter()
+                .copied()
+                .filter(|&participant| participant != identity)
+                .collect();
+            ctx.db.conversation().id().update(Conversation {
+                participants,
+                ..conversation
+            });
+        }
+    }
+
+    affected += ctx.db.channel_member().identity().delete(identity);
+    affected += ctx.db.channel_invite().invitee().delete(identity);
+    affected += ctx.db.friend_request().from().delete(identity);
+    affected += ctx.db.friend_request().to().delete(identity);
+    affected += ctx.db.friendship().a().delete(identity);
+    affected += ctx.db.friendship().b().delete(identity);
+    affected += ctx.db.block().blocker().delete(identity);
+    affected += ctx.db.block().blocked().delete(identity);
+    affected += ctx.db.typing_indicator().identity().delete(identity);
+    affected += ctx.db.notification().recipient().delete(identity);
+    affected += ctx.db.mention().mentioned().delete(identity);
+    affected += ctx.db.seen_by().identity().delete(identity);
+    affected += ctx.db.read_marker().identity().delete(identity);
+    affected += ctx
+        .db
+        .conversation_read_marker()
+        .identity()
+        .delete(identity);
+    affected += ctx.db.search_result().owner().delete(identity);
+    affected += ctx.db.user_search_result().owner().delete(identity);
+    affected += ctx.db.loaded_channel_member().owner().delete(identity);
+    affected += ctx.db.unread_count().owner().delete(identity);
+    let roster_rows: Vec<u64> = ctx
+        .db
+        .loaded_channel_member()
+        .iter()
+        .filter(|member| member.identity == identity)
+        .map(|member| member.id)
+        .collect();
+    affected += roster_rows.len() as u64;
+    for id in roster_rows {
+        ctx.db.loaded_channel_member().id().delete(id);
+    }
+    affected += ctx.db.name_check_result().owner().delete(identity) as u64;
+    affected += ctx.db.profile_view().owner().delete(identity) as u64;
+    let views: Vec<Identity> = ctx
+        .db
+        .profile_view()
+        .iter()
+        .filter(|view| view.identity == identity)
+        .map(|view| view.owner)
+        .collect();
+    affected += views.len() as u64;
+    for owner in views {
+        ctx.db.profile_view().owner().delete(owner);
+    }
+    affected += ctx.db.recent_send_key().sender().delete(identity);
+    affected += ctx.db.loaded_message().owner().delete(identity);
+    affected += ctx.db.loaded_notification().owner().delete(identity);
+    affected += ctx.db.user_badge().identity().delete(identity);
+    affected += ctx.db.user_stats().identity().delete(identity) as u64;
+    affected += ctx.db.progression().identity().delete(identity) as u64;
+    affected += ctx.db.send_state().identity().delete(identity) as u64;
+    affected += ctx.db.send_rejection().identity().delete(identity) as u64;
+    affected += ctx.db.mute().identity().delete(identity) as u64;
+    affected += ctx.db.pending_message().sender().delete(identity);
+    affected += ctx.db.welcome().identity().delete(identity) as u64;
+    affected += ctx.db.notification_prefs().identity().delete(identity) as u64;
+    affected += ctx.db.session().identity().delete(identity);
+    affected += ctx.db.scheduled_message().sender().delete(identity);
+    let graces: Vec<u64> = ctx
+        .db
+        .offline_grace_schedule()
+        .iter()
+        .filter(|grace| grace.identity == identity)
+        .map(|grace| grace.scheduled_id)
+        .collect();
+    affected += graces.len() as u64;
+    for scheduled_id in graces {
+        ctx.db
+            .offline_grace_schedule()
+            .scheduled_id()
+            .delete(scheduled_id);
+    }
+    if let Some(user) = ctx.db.user().identity().find(identity) {
+        adjust_room_stats(ctx, -(user.online as i64), -1, 0);
+        ctx.db.user().identity().delete(identity);
+        affected += 1;
+    }
+    affected
+}
+
+/// Deletes the rows that copy a message's content: its links and attachments, and any
+/// search results and loaded copies of it. Returns how many rows were deleted.
+fn delete_message_copies(ctx: &ReducerContext, message_id: u64) -> u64 {
+    let mut affected = ctx.db.attachment().message_id().delete(message_id);
+    affected += ctx.db.message_link().message_id().delete(message_id);
+    let search_results: Vec<u64> = ctx
+        .db
+        .search_result()
+        .iter()
+        .filter(|result| result.message_id == message_id)
+        .map(|result| result.id)
+        .collect();
+    affected += search_results.len() as u64;
+    for id in search_results {
+        ctx.db.search_result().id().delete(id);
+    }
+    let loaded: Vec<u64> = ctx
+        .db
+        .loaded_message()
+        .iter()
+        .filter(|loaded| loaded.message_id == message_id)
+        .map(|loaded| loaded.id)
+        .collect();
+    affected += loaded.len() as u64;
+    for id in loaded {
+        ctx.db.loaded_message().id().delete(id);
+    }
+    affected
+}
+
+/// `DELETED_USER` if `other` is the erased user, otherwise `other`.
+fn scrub_identity(other: Identity, erased: Identity) -> Identity {
+    if other == erased {
+        DELETED_USER
+    } else {
+        other
+    }
+}
+
+/// Blanks a message written or first posted by the erased user, and stops crediting them.
+fn erase_message(message: Message, erased: Identity) -> Message {
+    let text = if message.sender == erased || message.forwarded_from == Some(erased) {
+        String::new()
+    } else {
+        message.text
+    };
+    Message {
+        sender: scrub_identity(message.sender, erased),
+        forwarded_from: message
+            .forwarded_from
+            .map(|from| scrub_identity(from, erased)),
+        text,
+        ..message
+    }
+}
+
+/// Blanks a conversation message written by the erased user.
+fn erase_conversation_message(
+    message: ConversationMessage,
+    erased: Identity,
+) -> ConversationMessage {
+    if message.sender != erased {
+        return message;
+    }
+    ConversationMessage {
+        sender: DELETED_USER,
+        text: String::new(),
+        ..message
+    }
+}
+
+/// A report with the erased user scrubbed, or `None` if they filed it and it must go.
+fn scrub_report(report: Report, erased: Identity) -> Option<Report> {
+    if report.reporter == erased {
+        return None;
+    }
+    Some(Report {
+        handled_by: report.handled_by.map(|by| scrub_identity(by, erased)),
+        ..report
+    })
+}
+
+/// An audit log entry with the erased user scrubbed as its actor or target.
+fn scrub_audit_entry(entry: AuditLog, erased: Identity) -> AuditLog {
+    AuditLog {
+        actor: scrub_identity(entry.actor, erased),
+        target: entry.target.map(|target| scrub_identity(target, erased)),
+        ..entry
+    }
+}
+
+/// An allowlist entry with the erased user scrubbed, or `None` if it's for them.
+fn scrub_allowlist_entry(entry: Allowlist, erased: Identity) -> Option<Allowlist> {
+    if entry.identity == erased {
+        return None;
+    }
+    Some(Allowlist {
+        added_by: scrub_identity(entry.added_by, erased),
+        ..entry
+    })
+}
+
+/// Removes or anonymizes the rows that mention a user without belonging to them,
+/// returning how many rows were affected.
+fn scrub_references(ctx: &ReducerContext, identity: Identity) -> u64 {
+    let mut affected = 0;
+
+    let reports: Vec<Report> = ctx
+        .db
+        .report()
+        .iter()
+        .filter(|report| report.reporter == identity || report.handled_by == Some(identity))
+        .collect();
+    affected += reports.len() as u64;
+    for report in reports {
+        let id = report.id;
+        match scrub_report(report, identity) {
+            Some(report) => {
+                ctx.db.report().id().update(report);
+            }
+            None => {
+                ctx.db.report().id().delete(id);
+            }
+        }
+    }
+
+    let entries: Vec<AuditLog> = ctx
+        .db
+        .audit_log()
+        .iter()
+        .filter(|entry| entry.actor == identity || entry.target == Some(identity))
+        .collect();
+    affected += entries.len() as u64;
+    for entry in entries {
+        ctx.db
+            .audit_log()
+            .id()
+            .update(scrub_audit_entry(entry, identity));
+    }
+
+    let channels: Vec<Channel> = ctx
+        .db
+        .channel()
+        .iter()
+        .filter(|channel| channel.creator == identity)
+        .collect();
+    affected += channels.len() as u64;
+    for channel in channels {
+        ctx.db.channel().id().update(Channel {
+            creator: DELETED_USER,
+            ..channel
+        });
+    }
+
+    let conversations: Vec<Conversation> = ctx
+        .db
+        .conversation()
+        .iter()
+        .filter(|conversation| conversation.creator == identity)
+        .collect();
+    affected += conversations.len() as u64;
+    for conversation in conversations {
+        ctx.db.conversation().id().update(Conversation {
+            creator: DELETED_USER,
+            ..conversation
+        });
+    }
+
+    let polls: Vec<Poll> = ctx
+        .db
+        .poll()
+        .iter()
+        .filter(|poll| poll.creator == identity)
+        .collect();
+    affected += polls.len() as u64;
+    for poll in polls {
+        ctx.db.poll().id().update(Poll {
+            creator: DELETED_USER,
+            ..poll
+        });
+    }
+
+    let badges: Vec<UserBadge> = ctx
+        .db
+        .user_badge()
+        .iter()
+        .filter(|badge| badge.granted_by == identity)
+        .collect();
+    affected += badges.len() as u64;
+    for badge in badges {
+        ctx.db.user_badge().id().update(UserBadge {
+            granted_by: DELETED_USER,
+            ..badge
+        });
+    }
+
+    let allowlisted: Vec<Allowlist> = ctx
+        .db
+        .allowlist()
+        .iter()
+        .filter(|entry| entry.identity == identity || entry.added_by == identity)
+        .collect();
+    affected += allowlisted.len() as u64;
+    for entry in allowlisted {
+        match scrub_allowlist_entry(entry, identity) {
+            Some(entry) => {
+                ctx.db.allowlist().identity().update(entry);
+            }
+            None => {
+                ctx.db.allowlist().identity().delete(identity);
+            }
+        }
+    }
+
+    let blobs: Vec<ExportBlob> = ctx.db.export_blob().iter().collect();
+    for blob in blobs {
+        match scrub_export(&blob.json, identity) {
+            Some((_, 0)) => continue,
+            Some((json, scrubbed)) => {
+                affected += scrubbed;
+                ctx.db
+                    .export_blob()
+                    .id()
+                    .update(ExportBlob { json, ..blob });
+            }
+            None => {
+                affected += 1;
+                ctx.db.export_blob().id().delete(blob.id);
+            }
+        }
+    }
+
+    let invites: Vec<u64> = ctx
+        .db
+        .channel_invite()
+        .iter()
+        .filter(|invite| invite.inviter == identity)
+        .map(|invite| invite.id)
+        .collect();
+    affected += invites.len() as u64;
+    for id in invites {
+        ctx.db.channel_invite().id().delete(id);
+    }
+
+    let notifications: Vec<u64> = ctx
+        .db
+        .notification()
+        .iter()
+        .filter(|notification| notification.sender == identity)
+        .map(|notification| notification.id)
+        .collect();
+    affected += notifications.len() as u64;
+    for id in notifications {
+        ctx.db.notification().id().delete(id);
+    }
+
+    let mentions: Vec<u64> = ctx
+        .db
+        .mention()
+        .iter()
+        .filter(|mention| mention.mentioner == identity)
+        .map(|mention| mention.id)
+        .collect();
+    affected += mentions.len() as u64;
+    for id in mentions {
+        ctx.db.mention().id().delete(id);
+    }
+
+    let loaded_notifications: Vec<u64> = ctx
+        .db
+        .loaded_notification()
+        .iter()
+        .filter(|loaded| loaded.sender == identity)
+        .map(|loaded| loaded.id)
+        .collect();
+    affected += loaded_notifications.len() as u64;
+    for id in loaded_notifications {
+        ctx.db.loaded_notification().id().delete(id);
+    }
+
+    let pins: Vec<Pin> = ctx
+        .db
+        .pin()
+        .iter()
+        .filter(|pin| pin.pinned_by == identity)
+        .collect();
+    affected += pins.len() as u64;
+    for pin in pins {
+        ctx.db.pin().id().update(Pin {
+            pinned_by: DELETED_USER,
+            ..pin
+        });
+    }
+
+    let search_results: Vec<u64> = ctx
+        .db
+        .search_result()
+        .iter()
+        .filter(|result| result.sender == identity)
+        .map(|result| result.id)
+        .collect();
+    affected += search_results.len() as u64;
+    for id in search_results {
+        ctx.db.search_result().id().delete(id);
+    }
+
+    let loaded: Vec<u64> = ctx
+        .db
+        .loaded_message()
+        .iter()
+        .filter(|loaded| loaded.sender == identity)
+        .map(|loaded| loaded.id)
+        .collect();
+    affected += loaded.len() as u64;
+    for id in loaded {
+        ctx.db.loaded_message().id().delete(id);
+    }
+
+    let user_results: Vec<u64> = ctx
+        .db
+        .user_search_result()
+        .iter()
+        .filter(|result| result.identity == identity)
+        .map(|result| result.id)
+        .collect();
+    affected += user_results.len() as u64;
+    for id in user_results {
+        ctx.db.user_search_result().id().delete(id);
+    }
+
+    let ranks: Vec<u32> = ctx
+        .db
+        .leaderboard()
+        .iter()
+        .filter(|entry| entry.identity == identity)
+        .map(|entry| entry.rank)
+        .collect();
+    affected += ranks.len() as u64;
+    for rank in ranks {
+        ctx.db.leaderboard().rank().delete(rank);
+    }
+
+    affected
+}
+
+#[reducer(client_disconnected)]
+// Called when a client disconnects from SpacetimeDB database server
+pub fn identity_disconnected(ctx: &ReducerContext) {
+    match open_sessions(ctx, ctx.sender)
+        .into_iter()
+        .max_by_key(|session| session.connected_at)
+    {
+        Some(session) => {
+            ctx.db.session().id().update(Session {
+                disconnected_at: Some(ctx.timestamp),
+                ..session
+            });
+        }
+        None => log::warn!(
+            "Disconnect event with no open session for identity {:?}",
+            ctx.sender
+        ),
+    }
+
+    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
+        // Don't flip `online` yet, so a quick reconnect is invisible to other clients.
+        ctx.db.user().identity().update(User {
+            last_seen: ctx.timestamp,
+            pending_offline_at: Some(ctx.timestamp),
+            ..user
+        });
+        let grace = Duration::from_secs(config(ctx).offline_grace_secs);
+        let run_at = ctx
+            .timestamp
+            .checked_add_duration(grace)
+            .unwrap_or(ctx.timestamp);
+        ctx.db
+            .offline_grace_schedule()
+            .insert(OfflineGraceSchedule {
+                scheduled_id: 0,
+                scheduled_at: run_at.into(),
+                identity: ctx.sender,
+                disconnected_at: ctx.timestamp,
+            });
+    } else {
+        // This branch should be unreachable,
+        // as it doesn't make sense for a client to disconnect without connecting first.
+        log::warn!(
+            "Disconnect event for unknown user with identity {:?}",
+            ctx.sender
+        );
+    }
+}
+
+#[reducer]
+/// Marks a disconnected user offline if they haven't reconnected since.
+pub fn finish_disconnect(
+    ctx: &ReducerContext,
+    schedule: OfflineGraceSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Reducer `finish_disconnect` may only be invoked by the scheduler".to_string());
+    }
+
+    if let Some(user) = ctx.db.user().identity().find(schedule.identity) {
+        // A later disconnect has its own schedule row, so only the latest one applies.
+        if user.pending_offline_at == Some(schedule.disconnected_at) {
+            adjust_room_stats(ctx, online_delta(user.online, false), 0, 0);
+            ctx.db.user().identity().update(User {
+                online: false,
+                pending_offline_at: None,
+                ..user
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer every ~30 seconds to show they're still connected.
+pub fn heartbeat(ctx: &ReducerContext) -> Result<(), String> {
+    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
+        adjust_room_stats(ctx, online_delta(user.online, true), 0, 0);
+        ctx.db.user().identity().update(User {
+            online: true,
+            last_seen: ctx.timestamp,
+            ..user
+        });
+        Ok(())
+    } else {
+        Err("Cannot heartbeat for unknown user".to_string())
+    }
+}
+
+#[reducer]
+/// Deletes users who connected but were never authorized or named and haven't been back in a while.
+pub fn prune_inactive_users(
+    ctx: &ReducerContext,
+    _schedule: PruneUsersSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err(
+            "Reducer `prune_inactive_users` may only be invoked by the scheduler".to_string(),
+        );
+    }
+
+    let max_age = Duration::from_secs(config(ctx).prune_inactive_after_days * 24 * 60 * 60);
+    let inactive: Vec<Identity> = ctx
+        .db
+        .user()
+        .iter()
+        .filter(|user| never_activated(user, ctx.timestamp, max_age))
+        .map(|user| user.identity)
+        .filter(|&identity| !has_lasting_records(ctx, identity))
+        .collect();
+    // Only the user's own bookkeeping is left, such as sessions and preferences.
+    for &identity in &inactive {
+        remove_account(ctx, identity, MessageFate::Delete);
+    }
+    log::info!("Pruned {} inactive users", inactive.len());
+    Ok(())
+}
+
+/// Whether a user connected but was never authorized or named, and has been gone
+/// for longer than `max_age`.
+fn never_activated(user: &User, now: Timestamp, max_age: Duration) -> bool {
+    !user.authorized
+        && user.username.is_none()
+        && !user.online
+        && now
+            .duration_since(user.last_seen)
+            .is_some_and(|elapsed| elapsed > max_age)
+}
+
+/// Whether anything beyond a user's own bookkeeping refers to them: their messages,
+/// moderation records such as mutes, reports and audit entries, pending messages,
+/// allowlist entries, or memberships and links with other users.
+fn has_lasting_records(ctx: &ReducerContext, identity: Identity) -> bool {
+    ctx.db.message().sender().filter(identity).next().is_some()
+        || ctx.db.mute().identity().find(identity).is_some()
+        || ctx
+            .db
+            .pending_message()
+            .sender()
+            .filter(identity)
+            .next()
+            .is_some()
+        || ctx.db.allowlist().identity().find(identity).is_some()
+        || ctx
+            .db
+            .channel_member()
+            .identity()
+            .filter(identity)
+            .next()
+            .is_some()
+        || ctx
+            .db
+            .channel_invite()
+            .invitee()
+            .filter(identity)
+            .next()
+            .is_some()
+        || ctx
+            .db
+            .conversation_member()
+            .identity()
+            .filter(identity)
+            .next()
+            .is_some()
+        || ctx
+            .db
+            .friend_request()
+            .from()
+            .filter(identity)
+            .next()
+            .is_some()
+        || ctx
+            .db
+            .friend_request()
+            .to()
+            .filter(identity)
+            .next()
+            .is_some()
+        || ctx.db.friendship().a().filter(identity).next().is_some()
+        || ctx.db.friendship().b().filter(identity).next().is_some()
+        || ctx.db.block().blocker().filter(identity).next().is_some()
+        || ctx.db.block().blocked().filter(identity).next().is_some()
+        || ctx
+            .db
+            .report()
+            .iter()
+            .any(|report| report.reporter == identity || report.handled_by == Some(identity))
+        || ctx
+            .db
+            .audit_log()
+            .iter()
+            .any(|entry| entry.actor == identity || entry.target == Some(identity))
+}
+
+/// Whether a client last seen at `last_seen` has gone longer than `HEARTBEAT_TIMEOUT` without one.
+fn heartbeat_stale(last_seen: Timestamp, now: Timestamp) -> bool {
+    now.duration_since(last_seen)
+        .is_some_and(|elapsed| elapsed > HEARTBEAT_TIMEOUT)
+}
+
+#[reducer]
+/// Marks users offline whose clients have stopped sending heartbeats.
+pub fn sweep_presence(
+    ctx: &ReducerContext,
+    _schedule: PresenceSweepSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Reducer `sweep_presence` may only be invoked by the scheduler".to_string());
+    }
+
+    let stale: Vec<User> = ctx
+        .db
+        .user()
+        .iter()
+        .filter(|user| user.online && heartbeat_stale(user.last_seen, ctx.timestamp))
+        .collect();
+    adjust_room_stats(ctx, -(stale.len() as i64), 0, 0);
+    for user in stale {
+        ctx.db.user().identity().update(User {
+            online: false,
+            ..user
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_name_folds_case_width_and_accents() {
+        assert_eq!(normalize_name("Alice"), "alice");
+        assert_eq!(normalize_name("ＡＬＩＣＥ"), "alice");
+        assert_eq!(normalize_name("CAFÉ"), "café");
+        // A decomposed accent composes to the same form as the precomposed one
+        assert_eq!(normalize_name("Cafe\u{301}"), normalize_name("café"));
+        assert_ne!(normalize_name("cafe"), normalize_name("café"));
+    }
+
+    #[test]
+    fn grapheme_len_counts_emoji_sequences_once() {
+        assert_eq!(grapheme_len(""), 0);
+        assert_eq!(grapheme_len("abc"), 3);
+        // ZWJ family
+        assert_eq!(grapheme_len("👨\u{200d}👩\u{200d}👧"), 1);
+        // Regional indicator flag
+        assert_eq!(grapheme_len("🇯🇵"), 1);
+        // Skin-tone modifier
+        assert_eq!(grapheme_len("👍🏽"), 1);
+        assert_eq!(grapheme_len("hi 👍🏽🇯🇵"), 5);
+    }
+
+    #[test]
+    fn day_index_rolls_over_at_utc_midnight() {
+        let at = |micros: i64| day_index(Timestamp::from_micros_since_unix_epoch(micros));
+        assert_eq!(at(0), 0);
+        assert_eq!(at(MICROS_PER_DAY - 1), 0);
+        assert_eq!(at(MICROS_PER_DAY), 1);
+        assert_eq!(at(20_000 * MICROS_PER_DAY + 1), 20_000);
+        // Pre-epoch timestamps clamp to day 0 rather than wrapping
+        assert_eq!(at(-1), 0);
+    }
+
+    #[test]
+    fn streak_grows_daily_and_resets_after_a_gap() {
+        assert_eq!(next_streak(None, 0, 10), 1);
+        assert_eq!(next_streak(Some(10), 1, 10), 1);
+        assert_eq!(next_streak(Some(10), 1, 11), 2);
+        assert_eq!(next_streak(Some(11), 2, 12), 3);
+        assert_eq!(next_streak(Some(10), 5, 12), 1);
+    }
+
+    #[test]
+    fn message_length_counts_graphemes_not_chars() {
+        // Each "é" here is two chars but one grapheme
+        let text = "e\u{301}".repeat(Config::default().max_message_len as usize);
+        assert_eq!(
+            text.chars().count(),
+            2 * Config::default().max_message_len as usize
+        );
+        assert_eq!(
+            grapheme_len(&text),
+            Config::default().max_message_len as usize
+        );
+        // Decomposed Hangul jamo form a single syllable
+        assert_eq!(grapheme_len("\u{1100}\u{1161}\u{11a8}"), 1);
+    }
+
+    fn identity(byte: u8) -> Identity {
+        Identity::from_byte_array([byte; 32])
+    }
+
+    fn message(id: u64, channel_id: u64, sender: u8, sent: i64, text: &str) -> Message {
+        Message {
+            id,
+            channel_id,
+            sender: identity(sender),
+            sent: Timestamp::from_micros_since_unix_epoch(sent),
+            text: text.to_string(),
+            edited_at: None,
+            hidden: false,
+            expires_at: None,
+            forwarded_from: None,
+            forwarded_message_id: None,
+            seq: 0,
+            kind: MessageKind::Normal,
+            dummy_join: true,
+        }
+    }
+
+    fn ids(messages: &[Message]) -> Vec<u64> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn friendship_pair_is_order_independent() {
+        let (a, b) = (identity(1), identity(2));
+        assert_eq!(friendship_pair(a, b), friendship_pair(b, a));
+        let (low, high) = friendship_pair(b, a);
+        assert!(low < high);
+        assert_eq!(friendship_pair(a, a), (a, a));
+    }
+
+    #[test]
+    fn typing_scope_ids_keep_scopes_apart() {
+        assert_eq!(
+            typing_scope_ids(TypingScope::Global),
+            (GLOBAL_CHANNEL_ID, 0)
+        );
+        assert_eq!(typing_scope_ids(TypingScope::Channel(7)), (7, 0));
+        assert_eq!(
+            typing_scope_ids(TypingScope::Conversation(7)),
+            (GLOBAL_CHANNEL_ID, 7)
+        );
+    }
+
+    #[test]
+    fn find_links_requires_a_scheme() {
+        let links: Vec<&str> =
+            find_links("see https://example.com/a?b=c and HTTP://X.org, not example.com").collect();
+        assert_eq!(links, ["https://example.com/a?b=c", "HTTP://X.org,"]);
+        assert_eq!(
+            find_links("(https://example.com)").collect::<Vec<_>>(),
+            ["https://example.com)"]
+        );
+        assert_eq!(find_links("ftp://example.com www.example.com").count(), 0);
+    }
+
+    #[test]
+    fn default_color_is_stable_and_never_black_or_white() {
+        assert_eq!(default_color(identity(0x00)), 0x808080);
+        assert_eq!(default_color(identity(0xFF)), 0x808080);
+        let mut bytes = [0; 32];
+        bytes[..3].copy_from_slice(&[0x12, 0x34, 0x56]);
+        let color = default_color(Identity::from_byte_array(bytes));
+        assert_eq!(color, default_color(Identity::from_byte_array(bytes)));
+        assert!(color <= 0xFFFFFF);
+    }
+
+    #[test]
+    fn level_for_xp_changes_exactly_at_thresholds() {
+        assert_eq!(level_for_xp(0), 1);
+        assert_eq!(level_for_xp(9), 1);
+        assert_eq!(level_for_xp(10), 2);
+        assert_eq!(level_for_xp(49), 2);
+        assert_eq!(level_for_xp(50), 3);
+        assert_eq!(level_for_xp(24_999), 9);
+        assert_eq!(level_for_xp(25_000), LEVEL_THRESHOLDS.len() as u32);
+        assert_eq!(level_for_xp(u64::MAX), LEVEL_THRESHOLDS.len() as u32);
+    }
+
+    #[test]
+    fn scrub_export_erases_only_the_target() {
+        let exported = |sender: Identity, text: &str| ExportedMessage {
+            id: 1,
+            channel_id: GLOBAL_CHANNEL_ID,
+            sender: sender.to_hex().to_string(),
+            sent: 0,
+            text: text.to_string(),
+            edited_at: None,
+            hidden: false,
+            expires_at: None,
+        };
+        let (target, other) = (identity(1), identity(2));
+        let json =
+            serde_json::to_string(&[exported(target, "secret"), exported(other, "hi")]).unwrap();
+
+        let (scrubbed_json, scrubbed) = scrub_export(&json, target).unwrap();
+        assert_eq!(scrubbed, 1);
+        let messages: Vec<ExportedMessage> = serde_json::from_str(&scrubbed_json).unwrap();
+        assert_eq!(messages[0].sender, DELETED_USER.to_hex().to_string());
+        assert_eq!(messages[0].text, "");
+        assert_eq!(messages[1].sender, other.to_hex().to_string());
+        assert_eq!(messages[1].text, "hi");
+
+        assert!(scrub_export("not json", target).is_none());
+    }
+
+    #[test]
+    fn is_valid_locale_accepts_language_and_optional_region() {
+        for locale in ["en", "fil", "en-US", "es-419"] {
+            assert!(is_valid_locale(locale), "{locale}");
+        }
+        for locale in [
+            "", "e", "engl", "EN", "en-us", "en-USA", "es-41", "en-US-x", "en_US", "en-",
+        ] {
+            assert!(!is_valid_locale(locale), "{locale}");
+        }
+    }
+
+    #[test]
+    fn online_count_tracks_connect_and_disconnect_sequences() {
+        // Replays transitions for two users, reconnecting while already online included.
+        let mut online = [false; 2];
+        let mut count = 0;
+        let mut step = |user: usize, now_online: bool| {
+            let (next, clamped) = apply_count_delta(count, online_delta(online[user], now_online));
+            assert!(!clamped);
+            online[user] = now_online;
+            count = next;
+            count
+        };
+        assert_eq!(step(0, true), 1);
+        assert_eq!(step(0, true), 1);
+        assert_eq!(step(1, true), 2);
+        assert_eq!(step(0, false), 1);
+        assert_eq!(step(0, false), 1);
+        assert_eq!(step(1, true), 1);
+        assert_eq!(step(1, false), 0);
+    }
+
+    #[test]
+    fn apply_count_delta_clamps_at_zero() {
+        assert_eq!(apply_count_delta(3, -1), (2, false));
+        assert_eq!(apply_count_delta(3, 2), (5, false));
+        assert_eq!(apply_count_delta(0, -1), (0, true));
+    }
+
+    #[test]
+    fn is_allowed_name_char_allows_letters_digits_and_separators() {
+        for c in ['a', 'Z', '7', 'é', 'ж', '名', '_', '-', ' '] {
+            assert!(is_allowed_name_char(c), "{c:?}");
+        }
+        for c in ['@', '.', '!', '\n', '\t', '\u{200B}', '😀'] {
+            assert!(!is_allowed_name_char(c), "{c:?}");
+        }
+    }
+
+    #[test]
+    fn reaction_counts_stay_consistent_across_interleavings() {
+        // Each sequence is a series of adds (true) and removes (false) by different users.
+        let cases: [(&[bool], Option<u32>); 5] = [
+            (&[true, true, false], Some(1)),
+            (&[true, false, true], Some(1)),
+            (&[true, true, false, false], None),
+            (&[true, true, true], Some(3)),
+            (&[true, false, false], None),
+        ];
+        for (steps, expected) in cases {
+            let count = steps
+                .iter()
+                .fold(None, |count, &added| next_reaction_count(count, added));
+            assert_eq!(count, expected, "{steps:?}");
+        }
+    }
+
+    #[test]
+    fn parse_identity_hex_checks_length_and_digits() {
+        let hex = identity(0xab).to_hex().to_string();
+        assert_eq!(parse_identity_hex(&hex), Ok(identity(0xab)));
+        assert_eq!(
+            parse_identity_hex(&hex[..63]),
+            Err("expected 64 hex chars, got 63".to_string())
+        );
+        assert!(parse_identity_hex("").is_err());
+        let not_hex = format!("zz{}", &hex[2..]);
+        assert!(parse_identity_hex(&not_hex)
+            .unwrap_err()
+            .starts_with("not valid hex"));
+    }
+
+    #[test]
+    fn count_unread_counts_messages_after_the_marker() {
+        let ids = [3, 5, 8];
+        assert_eq!(count_unread(ids, None), 3);
+        assert_eq!(count_unread(ids, Some(4)), 2);
+        assert_eq!(count_unread(ids, Some(3)), 2);
+        // mark_channel_read moves the marker to the latest message
+        assert_eq!(count_unread(ids, ids.iter().copied().max()), 0);
+        assert_eq!(count_unread([], None), 0);
+    }
+
+    #[test]
+    fn expand_shortcodes_replaces_known_codes_only() {
+        let expand = |text: &str| {
+            expand_shortcodes_with(text, |code| match code {
+                "smile" => Some("😄".to_string()),
+                "tada" => Some("🎉".to_string()),
+                _ => None,
+            })
+        };
+        // Adjacent codes
+        assert_eq!(expand(":smile::tada:"), "😄🎉");
+        assert_eq!(expand(":smile:smile:"), "😄smile:");
+        // Codes at either end of the text
+        assert_eq!(expand(":smile: hi"), "😄 hi");
+        assert_eq!(expand("hi :tada:"), "hi 🎉");
+        // Colons inside words and unknown codes are left alone
+        assert_eq!(expand("meet at 10:30:00"), "meet at 10:30:00");
+        assert_eq!(expand("re: :nope: ok"), "re: :nope: ok");
+        assert_eq!(expand("a:b:smile:"), "a:b😄");
+        assert_eq!(expand("::smile::"), ":😄:");
+        assert_eq!(expand(":smile"), ":smile");
+        assert_eq!(expand(":"), ":");
+    }
+
+    #[test]
+    fn parse_slash_command_recognizes_commands() {
+        let post = |kind, text: &str| Ok(SlashCommand::Post(kind, text.to_string()));
+        assert_eq!(
+            parse_slash_command("hello"),
+            post(MessageKind::Normal, "hello")
+        );
+        assert_eq!(
+            parse_slash_command("/me waves"),
+            post(MessageKind::Action, "waves")
+        );
+        assert_eq!(
+            parse_slash_command("/shrug"),
+            post(MessageKind::Normal, SHRUG)
+        );
+        assert_eq!(
+            parse_slash_command("/shrug  oh well "),
+            post(MessageKind::Normal, &format!("oh well {SHRUG}"))
+        );
+        assert_eq!(
+            parse_slash_command("/nick Bob"),
+            Ok(SlashCommand::Nick("Bob".to_string()))
+        );
+        assert_eq!(
+            parse_slash_command("//me"),
+            post(MessageKind::Normal, "/me")
+        );
+        assert_eq!(
+            parse_slash_command("/dance"),
+            Err("Unknown command".to_string())
+        );
+        assert_eq!(parse_slash_command("/"), Err("Unknown command".to_string()));
+        assert_eq!(
+            parse_slash_command("/ me"),
+            Err("Unknown command".to_string())
+        );
+    }
+
+    #[test]
+    fn search_hits_match_ignoring_case_newest_first() {
+        let messages = vec![
+            message(1, GLOBAL_CHANNEL_ID, 1, 10, "Lunch at noon?"),
+            message(2, GLOBAL_CHANNEL_ID, 2, 20, "no thanks"),
+            message(3, GLOBAL_CHANNEL_ID, 1, 30, "LUNCH it is"),
+        ];
+        assert_eq!(ids(&search_hits(messages, "lunch")), [3, 1]);
+
+        let many = (0..60).map(|i| message(i, GLOBAL_CHANNEL_ID, 1, i as i64, "spam"));
+        let hits = search_hits(many, "spam");
+        assert_eq!(hits.len(), MAX_SEARCH_RESULTS);
+        assert_eq!(hits[0].id, 59);
+    }
+
+    #[test]
+    fn messages_in_range_includes_both_bounds() {
+        let messages = || (1..=5).map(|i| message(i, GLOBAL_CHANNEL_ID, 1, i as i64 * 10, "hi"));
+        let at = Timestamp::from_micros_since_unix_epoch;
+        assert_eq!(
+            ids(&messages_in_range(messages(), at(20), at(40))),
+            [2, 3, 4]
+        );
+        assert_eq!(ids(&messages_in_range(messages(), at(21), at(39))), [3]);
+        assert_eq!(ids(&messages_in_range(messages(), at(30), at(30))), [3]);
+        assert!(messages_in_range(messages(), at(51), at(60)).is_empty());
+    }
+
+    #[test]
+    fn messages_in_range_sorts_out_of_order_timestamps() {
+        // Ids and send times disagree, as they do for imported messages
+        let messages = vec![
+            message(1, GLOBAL_CHANNEL_ID, 1, 300, "c"),
+            message(2, GLOBAL_CHANNEL_ID, 1, 100, "a"),
+            message(3, GLOBAL_CHANNEL_ID, 1, 200, "b"),
+        ];
+        let sorted = messages_in_range(
+            messages,
+            Timestamp::from_micros_since_unix_epoch(0),
+            Timestamp::from_micros_since_unix_epoch(1_000),
+        );
+        assert_eq!(ids(&sorted), [2, 3, 1]);
+    }
+
+    #[test]
+    fn message_ids_sent_by_keeps_only_that_sender() {
+        let messages = || {
+            [
+                message(1, GLOBAL_CHANNEL_ID, 1, 10, "a"),
+                message(2, GLOBAL_CHANNEL_ID, 2, 20, "b"),
+                message(3, 7, 1, 30, "c"),
+            ]
+        };
+        assert_eq!(message_ids_sent_by(messages(), identity(1)), [1, 3]);
+        assert!(message_ids_sent_by(messages(), identity(3)).is_empty());
+    }
+
+    #[test]
+    fn page_before_pages_are_contiguous_and_disjoint() {
+        // Gaps in the ids, as deletions leave them
+        let messages = || {
+            [1, 2, 4, 5, 7, 8, 9]
+                .into_iter()
+                .map(|id| message(id, GLOBAL_CHANNEL_ID, 1, id as i64, "hi"))
+        };
+        let first = ids(&page_before(messages(), None, 3));
+        assert_eq!(first, [9, 8, 7]);
+        let second = ids(&page_before(messages(), first.last().copied(), 3));
+        assert_eq!(second, [5, 4, 2]);
+        let third = ids(&page_before(messages(), second.last().copied(), 3));
+        assert_eq!(third, [1]);
+        assert!(page_before(messages(), Some(1), 3).is_empty());
+
+        let many = (1..=150).map(|id| message(id, GLOBAL_CHANNEL_ID, 1, id as i64, "hi"));
+        assert_eq!(page_before(many, None, 500).len(), MAX_PAGE_SIZE as usize);
+    }
+
+    #[test]
+    fn check_window_closes_one_tick_after_the_edge() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let sent = at(1_000_000);
+        let edge = at(1_000_000 + 15 * 60 * 1_000_000);
+        let past = at(1_000_000 + 15 * 60 * 1_000_000 + 1);
+        assert_eq!(check_window(sent, sent, 15, "edited"), Ok(()));
+        assert_eq!(check_window(sent, edge, 15, "edited"), Ok(()));
+        assert_eq!(
+            check_window(sent, past, 15, "edited"),
+            Err("Messages can only be edited within 15 minutes".to_string())
+        );
+    }
+
+    #[test]
+    fn heartbeat_goes_stale_after_ninety_seconds() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let last_seen = at(5_000_000);
+        assert!(!heartbeat_stale(last_seen, at(5_000_000 + 30_000_000)));
+        assert!(!heartbeat_stale(last_seen, at(5_000_000 + 90_000_000)));
+        assert!(heartbeat_stale(last_seen, at(5_000_000 + 90_000_001)));
+        // A heartbeat after the sweep counts the user as online again
+        assert_eq!(online_delta(false, true), 1);
+        assert!(!heartbeat_stale(at(100_000_000), at(100_000_000)));
+    }
+
+    fn usernames(names: &[(&str, u8)]) -> Vec<(String, Identity)> {
+        names
+            .iter()
+            .map(|&(name, byte)| (normalize_name(name), identity(byte)))
+            .collect()
+    }
+
+    #[test]
+    fn mentions_count_distinct_users_once() {
+        let names = usernames(&[("ann", 1), ("bob", 2), ("cy", 3)]);
+        let limit = 2;
+        let distinct = resolve_mentions_among("@ann @bob @cy hi", names.clone());
+        assert_eq!(distinct, [identity(1), identity(2), identity(3)]);
+        assert!(distinct.len() > limit);
+        let repeated = resolve_mentions_among("@ann @ANN @ann @ann", names.clone());
+        assert_eq!(repeated, [identity(1)]);
+        assert!(repeated.len() <= limit);
+        assert!(resolve_mentions_among("@nobody and ann@", names).is_empty());
+    }
+
+    #[test]
+    fn is_muted_refuses_until_the_mute_ends() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let mute = Mute {
+            identity: identity(1),
+            until: at(1_000),
+            auto_mutes: 1,
+            dummy_join: true,
+        };
+        assert!(is_muted(Some(&mute), at(999)));
+        assert!(!is_muted(Some(&mute), at(1_000)));
+        assert!(!is_muted(None, at(999)));
+    }
+
+    #[test]
+    fn leaderboard_ranks_by_count_with_stable_ties() {
+        let entries = leaderboard_entries([
+            (identity(3), 5),
+            (identity(1), 9),
+            (identity(4), 5),
+            (identity(2), 5),
+        ]);
+        let ranked: Vec<(u32, Identity, u64)> = entries
+            .iter()
+            .map(|entry| (entry.rank, entry.identity, entry.count))
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                (1, identity(1), 9),
+                (2, identity(2), 5),
+                (3, identity(3), 5),
+                (4, identity(4), 5),
+            ]
+        );
+
+        let many = leaderboard_entries((0..20).map(|i| (identity(i), i as u64)));
+        assert_eq!(many.len(), LEADERBOARD_SIZE);
+        assert_eq!(many[0].identity, identity(19));
+    }
+
+    #[test]
+    fn reactions_must_be_whitelisted() {
+        let mut allowed: HashSet<String> = DEFAULT_ALLOWED_REACTIONS
+            .iter()
+            .map(|emoji| emoji.to_string())
+            .collect();
+        let check = |allowed: &HashSet<String>, emoji: &str| {
+            check_reaction_allowed(emoji, |emoji| allowed.contains(emoji))
+        };
+        let first = DEFAULT_ALLOWED_REACTIONS[0];
+        assert_eq!(
+            check(&allowed, &format!(" {first} ")),
+            Ok(first.to_string())
+        );
+        assert_eq!(
+            check(&allowed, "🦀"),
+            Err("That reaction is not allowed".to_string())
+        );
+
+        allowed.insert(validate_reaction_emoji(" 🦀 ").unwrap());
+        assert_eq!(check(&allowed, "🦀"), Ok("🦀".to_string()));
+
+        assert!(validate_reaction_emoji("  ").is_err());
+        assert!(validate_reaction_emoji("a\u{7}").is_err());
+    }
+
+    fn report(reporter: u8, status: ReportStatus) -> Report {
+        Report {
+            id: 0,
+            message_id: 1,
+            reporter: identity(reporter),
+            reason: String::new(),
+            created_at: Timestamp::from_micros_since_unix_epoch(0),
+            status,
+            handled_by: None,
+            dummy_join: true,
+        }
+    }
+
+    #[test]
+    fn already_reported_only_counts_open_reports_by_the_reporter() {
+        let reports = [
+            report(1, ReportStatus::Open),
+            report(2, ReportStatus::Dismissed),
+        ];
+        assert!(already_reported(&reports, identity(1)));
+        // A report that was dealt with doesn't stop a new one
+        assert!(!already_reported(&reports, identity(2)));
+        assert!(!already_reported(&reports, identity(3)));
+    }
+
+    #[test]
+    fn reports_hide_a_message_exactly_at_the_threshold() {
+        let mut reports = vec![report(1, ReportStatus::Open), report(2, ReportStatus::Open)];
+        assert!(!reaches_hide_threshold(&reports, 3));
+        // A dismissed report doesn't count towards it
+        reports.push(report(3, ReportStatus::Dismissed));
+        assert!(!reaches_hide_threshold(&reports, 3));
+        reports.push(report(4, ReportStatus::Resolved));
+        assert!(reaches_hide_threshold(&reports, 3));
+        assert!(!reaches_hide_threshold(&reports, 0));
+    }
+
+    fn user(byte: u8) -> User {
+        new_user(
+            identity(byte),
+            format!("User-{byte:02x}"),
+            true,
+            false,
+            Timestamp::from_micros_since_unix_epoch(0),
+        )
+    }
+
+    #[test]
+    fn lazily_created_users_are_guests_and_returning_users_keep_their_name() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let guest = new_user(identity(1), "User-01".to_string(), false, true, at(10));
+        assert!(!guest.authorized && guest.online);
+        assert_eq!(guest.username, None);
+        assert_eq!(guest.nickname.as_deref(), Some("User-01"));
+        assert_eq!((guest.first_seen, guest.last_seen), (at(10), at(10)));
+
+        let named = User {
+            username: Some("alice".to_string()),
+            online: false,
+            pending_offline_at: Some(at(15)),
+            ..user(2)
+        };
+        let returned = mark_online(named, at(20));
+        assert_eq!(returned.username.as_deref(), Some("alice"));
+        assert!(returned.authorized && returned.online);
+        assert_eq!((returned.first_seen, returned.last_seen), (at(0), at(20)));
+        assert_eq!(returned.pending_offline_at, None);
+    }
+
+    #[test]
+    fn mention_delivery_respects_dnd_and_prefs() {
+        let prefs = NotificationPrefs::defaults(identity(1));
+        assert_eq!(
+            mention_delivery(&user(1), true, &prefs),
+            MentionDelivery::Notify
+        );
+        let dnd = User {
+            dnd: true,
+            ..user(1)
+        };
+        assert_eq!(
+            mention_delivery(&dnd, true, &prefs),
+            MentionDelivery::MentionOnly
+        );
+
+        let opted_out = NotificationPrefs {
+            notify_on_mention: false,
+            ..NotificationPrefs::defaults(identity(1))
+        };
+        assert_eq!(
+            mention_delivery(&user(1), true, &opted_out),
+            MentionDelivery::Skip
+        );
+        assert_eq!(
+            mention_delivery(&user(1), false, &prefs),
+            MentionDelivery::Skip
+        );
+        let guest = User {
+            authorized: false,
+            ..user(1)
+        };
+        assert_eq!(
+            mention_delivery(&guest, true, &prefs),
+            MentionDelivery::Skip
+        );
+    }
+
+    #[test]
+    fn export_json_has_an_entry_per_message() {
+        let json = export_json(&[
+            message(1, GLOBAL_CHANNEL_ID, 1, 10, "first"),
+            message(2, 7, 2, 20, "second"),
+        ])
+        .unwrap();
+        let exported: Vec<ExportedMessage> = serde_json::from_str(&json).unwrap();
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[0].text, "first");
+        assert_eq!(exported[1].sender, identity(2).to_hex().to_string());
+        assert_eq!((exported[1].channel_id, exported[1].sent), (7, 20));
+    }
+
+    #[test]
+    fn profile_cooldown_ends_exactly_after_a_minute() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let changed = Some(at(1_000_000));
+        assert_eq!(check_profile_cooldown(None, at(0)), Ok(()));
+        assert_eq!(
+            check_profile_cooldown(changed, at(1_000_000 + 59_999_999)),
+            Err("Please wait 60 seconds between profile changes".to_string())
+        );
+        assert_eq!(
+            check_profile_cooldown(changed, at(1_000_000 + 60_000_000)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn import_round_trips_an_export() {
+        let original = [
+            message(1, GLOBAL_CHANNEL_ID, 1, 10, "first"),
+            message(2, 7, 2, 20, "   "),
+            message(3, 7, 3, 30, "third"),
+        ];
+        let imported = parse_import(&export_json(&original).unwrap()).unwrap();
+        let summary: Vec<(u64, Identity, Timestamp, &str)> = imported
+            .iter()
+            .map(|message| {
+                (
+                    message.channel_id,
+                    message.sender,
+                    message.sent,
+                    message.text.as_str(),
+                )
+            })
+            .collect();
+        // The blank message is skipped
+        assert_eq!(
+            summary,
+            [
+                (GLOBAL_CHANNEL_ID, identity(1), original[0].sent, "first"),
+                (7, identity(3), original[2].sent, "third"),
+            ]
+        );
+
+        assert!(parse_import("not json")
+            .err()
+            .is_some_and(|err| err.starts_with("Invalid import JSON")));
+        let bad_sender = r#"[{"id":5,"channel_id":0,"sender":"abc","sent":0,"text":"hi","edited_at":null,"hidden":false}]"#;
+        assert_eq!(
+            parse_import(bad_sender).err().as_deref(),
+            Some("Invalid sender in message 5: expected 64 hex chars, got 3")
+        );
+    }
+
+    #[test]
+    fn hourly_xp_cap_rolls_over_with_the_hour() {
+        let hour = MICROS_PER_HOUR;
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let mut progression = Progression {
+            identity: identity(1),
+            xp: 0,
+            level: 1,
+            hour: 0,
+            xp_this_hour: 0,
+        };
+        let mut earn = |now: Timestamp| {
+            let (hour, xp_this_hour) = next_hourly_xp(&progression, now, 2)?;
+            progression.hour = hour;
+            progression.xp_this_hour = xp_this_hour;
+            progression.xp += 1;
+            Some(progression.xp)
+        };
+        assert_eq!(earn(at(5 * hour)), Some(1));
+        assert_eq!(earn(at(5 * hour + 1)), Some(2));
+        assert_eq!(earn(at(6 * hour - 1)), None);
+        // The cap starts over exactly at the next hour
+        assert_eq!(earn(at(6 * hour)), Some(3));
+        assert_eq!(earn(at(6 * hour + 10)), Some(4));
+        assert_eq!(earn(at(6 * hour + 20)), None);
+    }
+
+    #[test]
+    fn never_activated_users_are_unnamed_guests_gone_past_the_cutoff() {
+        let day = 24 * 60 * 60 * 1_000_000;
+        let max_age = Duration::from_secs(30 * 24 * 60 * 60);
+        let now = Timestamp::from_micros_since_unix_epoch(100 * day);
+        let guest = || User {
+            authorized: false,
+            last_seen: Timestamp::from_micros_since_unix_epoch(60 * day),
+            ..user(1)
+        };
+        assert!(never_activated(&guest(), now, max_age));
+
+        let recent = User {
+            last_seen: Timestamp::from_micros_since_unix_epoch(70 * day),
+            ..guest()
+        };
+        assert!(!never_activated(&recent, now, max_age));
+        let named = User {
+            username: Some("alice".to_string()),
+            ..guest()
+        };
+        assert!(!never_activated(&named, now, max_age));
+        let online = User {
+            online: true,
+            ..guest()
+        };
+        assert!(!never_activated(&online, now, max_age));
+        assert!(!never_activated(&user(1), now, max_age));
+    }
+
+    fn named(byte: u8, username: &str) -> User {
+        User {
+            username: Some(username.to_string()),
+            username_normalized: Some(normalize_name(username)),
+            nickname: None,
+            ..user(byte)
+        }
+    }
+
+    #[test]
+    fn user_search_matches_prefixes_in_username_order() {
+        let users = vec![
+            named(1, "alicia"),
+            named(2, "bob"),
+            named(3, "Alice"),
+            named(4, "malice"),
+            user(5),
+        ];
+        let found: Vec<Identity> = users_with_prefix(users, "ali")
+            .iter()
+            .map(|user| user.identity)
+            .collect();
+        assert_eq!(found, vec![identity(3), identity(1)]);
+    }
+
+    #[test]
+    fn user_search_is_capped() {
+        let users =
+            (0..MAX_USER_SEARCH_RESULTS as u8 + 5).map(|i| named(i, &format!("user{i:02}")));
+        let found = users_with_prefix(users, "user");
+        assert_eq!(found.len(), MAX_USER_SEARCH_RESULTS);
+        assert_eq!(found[0].username.as_deref(), Some("user00"));
+    }
+
+    #[test]
+    fn many_tiny_messages_exhaust_the_byte_budget() {
+        let config = Config {
+            byte_budget_max_bytes: 10,
+            ..Config::default()
+        };
+        let now = Timestamp::from_micros_since_unix_epoch(1_000_000);
+        let mut sender = user(1);
+        for _ in 0..5 {
+            sender = spend_byte_budget(&config, sender, 2, now).unwrap();
+        }
+        assert_eq!(sender.bytes_in_window, 10);
+        assert_eq!(
+            spend_byte_budget(&config, sender, 1, now).err().as_deref(),
+            Some("Message rate limit exceeded")
+        );
+    }
+
+    #[test]
+    fn byte_budget_refills_in_a_new_window() {
+        let config = Config {
+            byte_budget_max_bytes: 10,
+            byte_budget_window_secs: 60,
+            ..Config::default()
+        };
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let spent = spend_byte_budget(&config, user(1), 10, at(0)).unwrap();
+        assert!(spend_byte_budget(&config, spent, 1, at(59_999_999)).is_err());
+
+        let spent = spend_byte_budget(&config, user(1), 10, at(0)).unwrap();
+        let refilled = spend_byte_budget(&config, spent, 4, at(60_000_000)).unwrap();
+        assert_eq!(refilled.byte_window_start, Some(at(60_000_000)));
+        assert_eq!(refilled.bytes_in_window, 4);
+    }
+
+    #[test]
+    fn staff_are_exempt_from_spam_drops() {
+        assert!(!is_spam_exempt(&user(1)));
+        let moderator = User {
+            moderator: true,
+            ..user(1)
+        };
+        assert!(is_spam_exempt(&moderator));
+        let admin = User {
+            admin: true,
+            ..user(1)
+        };
+        assert!(is_spam_exempt(&admin));
+    }
+
+    #[test]
+    fn erasing_leaves_no_trace_of_the_identity() {
+        let (erased, other) = (identity(1), identity(2));
+
+        let own = erase_message(message(1, GLOBAL_CHANNEL_ID, 1, 0, "secret"), erased);
+        assert_eq!((own.sender, own.text.as_str()), (DELETED_USER, ""));
+        let forward = Message {
+            forwarded_from: Some(erased),
+            ..message(2, GLOBAL_CHANNEL_ID, 2, 0, "secret")
+        };
+        let forward = erase_message(forward, erased);
+        assert_eq!(forward.sender, other);
+        assert_eq!(forward.forwarded_from, Some(DELETED_USER));
+        assert_eq!(forward.text, "");
+        let unrelated = erase_message(message(3, GLOBAL_CHANNEL_ID, 2, 0, "hi"), erased);
+        assert_eq!((unrelated.sender, unrelated.text.as_str()), (other, "hi"));
+
+        let conversation_message = |sender| ConversationMessage {
+            id: 1,
+            conversation_id: 1,
+            sender,
+            sent: Timestamp::UNIX_EPOCH,
+            text: "secret".to_string(),
+        };
+        let erased_message = erase_conversation_message(conversation_message(erased), erased);
+        assert_eq!(erased_message.sender, DELETED_USER);
+        assert_eq!(erased_message.text, "");
+        let kept = erase_conversation_message(conversation_message(other), erased);
+        assert_eq!((kept.sender, kept.text.as_str()), (other, "secret"));
+
+        assert!(scrub_report(report(1, ReportStatus::Open), erased).is_none());
+        let handled = Report {
+            handled_by: Some(erased),
+            ..report(2, ReportStatus::Resolved)
+        };
+        let handled = scrub_report(handled, erased).unwrap();
+        assert_eq!(
+            (handled.reporter, handled.handled_by),
+            (other, Some(DELETED_USER))
+        );
+
+        let entry = AuditLog {
+            id: 1,
+            actor: erased,
+            action: "mute_user".to_string(),
+            target: Some(erased),
+            channel_id: None,
+            created_at: Timestamp::UNIX_EPOCH,
+            dummy_join: false,
+        };
+        let entry = scrub_audit_entry(entry, erased);
+        assert_eq!(
+            (entry.actor, entry.target),
+            (DELETED_USER, Some(DELETED_USER))
+        );
+
+        let allowlisted = |identity, added_by| Allowlist {
+            identity,
+            added_by,
+            added_at: Timestamp::UNIX_EPOCH,
+            dummy_join: false,
+        };
+        assert!(scrub_allowlist_entry(allowlisted(erased, other), erased).is_none());
+        let entry = scrub_allowlist_entry(allowlisted(other, erased), erased).unwrap();
+        assert_eq!((entry.identity, entry.added_by), (other, DELETED_USER));
+
+        assert_eq!(scrub_identity(erased, erased), DELETED_USER);
+        assert_eq!(scrub_identity(other, erased), other);
+    }
+
+    #[test]
+    fn generated_nicknames_are_distinct() {
+        let mut taken: Vec<String> = Vec::new();
+        for _ in 0..3 {
+            let nickname = free_nickname(identity(1), |candidate| {
+                taken.iter().any(|name| name == candidate)
+            });
+            taken.push(nickname);
+        }
+        assert_eq!(taken, vec!["User-010101", "User-010101-2", "User-010101-3"]);
+        assert_eq!(free_nickname(identity(2), |_| false), "User-020202");
+    }
+
+    #[test]
+    fn account_age_counts_whole_seconds_since_first_seen() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let joined = User {
+            first_seen: at(10_000_000),
+            ..user(1)
+        };
+        assert_eq!(account_age_secs(&joined, at(10_000_000)), 0);
+        assert_eq!(account_age_secs(&joined, at(14_999_999)), 4);
+        assert_eq!(account_age_secs(&joined, at(15_000_000)), 5);
+        // A clock that went backwards counts as a brand new account.
+        assert_eq!(account_age_secs(&joined, at(0)), 0);
+    }
+
+    #[test]
+    fn new_accounts_wait_out_the_minimum_age() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let joined = User {
+            first_seen: at(0),
+            ..user(1)
+        };
+        assert_eq!(
+            check_account_age(&joined, at(59_999_999), 60)
+                .err()
+                .as_deref(),
+            Some("Account too new to send messages")
+        );
+        assert!(check_account_age(&joined, at(60_000_000), 60).is_ok());
+        assert!(check_account_age(&joined, at(0), 0).is_ok());
+    }
+
+    #[test]
+    fn messages_expire_at_their_expiry_time() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        assert!(!is_expired(None, at(100)));
+        assert!(!is_expired(Some(at(100)), at(99)));
+        assert!(is_expired(Some(at(100)), at(100)));
+        assert!(is_expired(Some(at(100)), at(101)));
+    }
+
+    #[test]
+    fn delete_window_wording_and_disabled_window() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let sent = at(0);
+        let late = at(2 * 60 * 1_000_000 + 1);
+        assert_eq!(
+            check_window(sent, late, 1, "deleted"),
+            Err("Messages can only be deleted within 1 minute".to_string())
+        );
+        assert_eq!(
+            check_window(sent, late, 0, "deleted"),
+            Ok(()),
+            "a window of 0 never closes"
+        );
+        assert_eq!(check_window(sent, at(i64::MAX), 0, "deleted"), Ok(()));
+    }
+
+    #[test]
+    fn avatar_urls_must_be_short_http_urls() {
+        let url = "https://example.com/me.png";
+        assert_eq!(validate_avatar_url(url), Ok(Some(url.to_string())));
+        assert_eq!(
+            validate_avatar_url("  HTTP://example.com/me.png "),
+            Ok(Some("HTTP://example.com/me.png".to_string()))
+        );
+        assert_eq!(validate_avatar_url(""), Ok(None));
+        assert_eq!(validate_avatar_url("   "), Ok(None));
+
+        let not_http = Err("Avatars must be http(s) URLs".to_string());
+        assert_eq!(validate_avatar_url("javascript:alert(1)"), not_http);
+        assert_eq!(validate_avatar_url("data:image/png;base64,AAAA"), not_http);
+        assert_eq!(validate_avatar_url("https://example.com/a b.png"), not_http);
+
+        let longest = format!("https://{}", "a".repeat(MAX_AVATAR_URL_LEN - 8));
+        assert!(validate_avatar_url(&longest).is_ok());
+        assert_eq!(
+            validate_avatar_url(&format!("{longest}a")),
+            Err(format!(
+                "Avatar URLs must be at most {MAX_AVATAR_URL_LEN} characters"
+            ))
+        );
+    }
+
+    #[test]
+    fn mentions_prefer_the_longest_matching_username() {
+        let names = || usernames(&[("alice", 1), ("alice_b", 2)]);
+        assert_eq!(
+            resolve_mentions_among("hi @alice_b", names()),
+            vec![identity(2)]
+        );
+        assert_eq!(
+            resolve_mentions_among("hi @alice!", names()),
+            vec![identity(1)]
+        );
+        assert_eq!(
+            resolve_mentions_among("@alice and @Alice_B", names()),
+            vec![identity(1), identity(2)]
+        );
+        // A mention of an unknown longer name doesn't fall back to a shorter one
+        assert!(resolve_mentions_among("@alice_c", names()).is_empty());
+    }
+
+    #[test]
+    fn pins_are_capped_per_channel() {
+        let mut pinned = 0;
+        while check_pin_room(pinned).is_ok() {
+            pinned += 1;
+        }
+        assert_eq!(pinned, MAX_PINS);
+        assert_eq!(
+            check_pin_room(pinned),
+            Err(format!(
+                "A channel can have at most {MAX_PINS} pinned messages"
+            ))
+        );
+        // Unpinning one message frees its slot
+        assert!(check_pin_room(pinned - 1).is_ok());
+    }
+
+    fn block(blocker: u8, blocked: u8) -> Block {
+        Block {
+            id: 0,
+            blocker: identity(blocker),
+            blocked: identity(blocked),
+            created_at: Timestamp::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn blocking_is_one_directional() {
+        let (me, them) = (identity(1), identity(2));
+        assert!(check_recipient(me, them, true, &[]).is_ok());
+        assert!(check_recipient(me, them, true, &[block(2, 1)]).is_err());
+        // Having blocked someone doesn't stop you messaging them
+        assert!(check_recipient(me, them, true, &[block(1, 2)]).is_ok());
+        assert!(check_recipient(me, them, true, &[block(2, 3)]).is_ok());
+    }
+
+    #[test]
+    fn refused_recipients_all_get_the_same_wording() {
+        let (me, them) = (identity(1), identity(2));
+        let refused = Err("You cannot message this user".to_string());
+        assert_eq!(check_recipient(me, them, true, &[block(2, 1)]), refused);
+        // An unknown recipient is refused the same way, so blocks can't be probed for
+        assert_eq!(check_recipient(me, them, false, &[]), refused);
+    }
+
+    #[test]
+    fn only_a_just_created_guest_skips_authorization_to_set_a_name() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let guest = || new_user(identity(1), "User-010101".to_string(), false, true, at(0));
+        assert!(check_may_set_name(guest(), true).is_ok());
+        assert_eq!(
+            check_may_set_name(guest(), false).err().as_deref(),
+            Some("Unauthorized user attempted to perform an action")
+        );
+        assert!(check_may_set_name(user(1), false).is_ok());
+    }
+
+    #[test]
+    fn each_user_is_recorded_seeing_a_message_once() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let mut seen = Vec::new();
+        seen.extend(new_sighting(&seen, 7, identity(1), at(1)));
+        assert_eq!(seen.len(), 1);
+        assert!(new_sighting(&seen, 7, identity(1), at(2)).is_none());
+
+        seen.extend(new_sighting(&seen, 7, identity(2), at(3)));
+        let seen: Vec<(Identity, Timestamp)> = seen
+            .iter()
+            .map(|seen| (seen.identity, seen.seen_at))
+            .collect();
+        assert_eq!(seen, vec![(identity(1), at(1)), (identity(2), at(3))]);
+    }
+
+    #[test]
+    fn bulk_deletion_is_for_admins_and_scoped_to_the_target() {
+        let moderator = User {
+            moderator: true,
+            ..user(1)
+        };
+        let refused = Some("Only admins may perform this action");
+        assert_eq!(require_admin(user(1)).err().as_deref(), refused);
+        assert_eq!(require_admin(moderator).err().as_deref(), refused);
+        let admin = User {
+            admin: true,
+            ..user(1)
+        };
+        assert!(require_admin(admin).is_ok());
+
+        // Every channel's messages from the target go, and nobody else's
+        let messages = [
+            message(1, GLOBAL_CHANNEL_ID, 9, 10, "spam"),
+            message(2, GLOBAL_CHANNEL_ID, 2, 20, "reply"),
+            message(3, 7, 9, 30, "spam"),
+            message(4, 8, 9, 40, "spam"),
+        ];
+        assert_eq!(message_ids_sent_by(messages, identity(9)), [1, 3, 4]);
+    }
+
+    #[test]
+    fn names_need_two_graphemes() {
+        let check = |name: &str| validate_name_within(name.to_string(), 32);
+        assert_eq!(check("a"), Err("Name too short".to_string()));
+        assert_eq!(check(" a "), Err("Name too short".to_string()));
+        assert_eq!(check("ab"), Ok("ab".to_string()));
+        // An accented letter is one grapheme, however it's encoded
+        assert_eq!(check("e\u{301}"), Err("Name too short".to_string()));
+        assert_eq!(check(""), Err("Names must not be empty".to_string()));
+        assert_eq!(check("   "), Err("Names must not be empty".to_string()));
+    }
+
+    fn member(channel_id: u64, byte: u8) -> ChannelMember {
+        ChannelMember {
+            id: 0,
+            channel_id,
+            identity: identity(byte),
+            joined_at: Timestamp::UNIX_EPOCH,
+            is_moderator: false,
+            nickname: None,
+        }
+    }
+
+    #[test]
+    fn channel_roster_lists_every_member_for_members_only() {
+        let members = || {
+            vec![
+                (member(7, 1), named(1, "zed")),
+                (
+                    ChannelMember {
+                        nickname: Some("Bea".to_string()),
+                        is_moderator: true,
+                        ..member(7, 2)
+                    },
+                    named(2, "robert"),
+                ),
+                (
+                    member(7, 3),
+                    User {
+                        online: true,
+                        ..named(3, "amy")
+                    },
+                ),
+            ]
+        };
+        assert_eq!(
+            channel_roster(identity(9), members()).err().as_deref(),
+            Some("Not a member of this channel")
+        );
+
+        let roster = channel_roster(identity(1), members()).unwrap();
+        let rows: Vec<(Identity, &str, bool, bool)> = roster
+            .iter()
+            .map(|row| {
+                (
+                    row.identity,
+                    row.name.as_str(),
+                    row.online,
+                    row.is_moderator,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (identity(3), "amy", true, false),
+                (identity(2), "Bea", false, true),
+                (identity(1), "zed", false, false),
+            ]
+        );
+        assert!(roster
+            .iter()
+            .all(|row| row.owner == identity(1) && row.channel_id == 7));
+    }
+
+    fn channel(id: u64, name: &str, creator: u8) -> Channel {
+        Channel {
+            id,
+            name: name.to_string(),
+            creator: identity(creator),
+            created_at: Timestamp::UNIX_EPOCH,
+            topic: None,
+            invite_only: false,
+            archived: false,
+            category_id: None,
+            position: 0,
+            next_seq: 1,
+        }
+    }
+
+    #[test]
+    fn only_creators_and_admins_may_rename_channels() {
+        let general = channel(7, "general", 1);
+        let admin = |byte| User {
+            admin: true,
+            ..user(byte)
+        };
+        let moderator = User {
+            moderator: true,
+            ..user(2)
+        };
+        assert!(check_channel_owner(&general, &user(1), "rename").is_ok());
+        assert!(check_channel_owner(&general, &admin(1), "rename").is_ok());
+        assert!(check_channel_owner(&general, &admin(2), "rename").is_ok());
+        let refused = Some("Only the channel creator or an admin may rename a channel");
+        assert_eq!(
+            check_channel_owner(&general, &user(2), "rename")
+                .err()
+                .as_deref(),
+            refused
+        );
+        assert_eq!(
+            check_channel_owner(&general, &moderator, "rename")
+                .err()
+                .as_deref(),
+            refused
+        );
+    }
+
+    #[test]
+    fn channel_names_must_be_free() {
+        let taken = |name: &str| name == "random";
+        assert_eq!(
+            check_channel_name(" random ".to_string(), 32, taken),
+            Err("Channel name already taken".to_string())
+        );
+        assert_eq!(
+            check_channel_name(" lounge ".to_string(), 32, taken),
+            Ok("lounge".to_string())
+        );
+        assert_eq!(
+            check_channel_name("x".repeat(33), 32, taken),
+            Err("Channel names must be at most 32 characters".to_string())
+        );
+    }
+
+    #[test]
+    fn the_default_channel_cannot_be_deleted() {
+        assert_eq!(
+            check_channel_deletable(1, 1),
+            Err("The default channel cannot be deleted".to_string())
+        );
+        assert!(check_channel_deletable(7, 1).is_ok());
+
+        let random = channel(7, "random", 1);
+        assert!(check_channel_owner(&random, &user(1), "delete").is_ok());
+        assert_eq!(
+            check_channel_owner(&random, &user(2), "delete")
+                .err()
+                .as_deref(),
+            Some("Only the channel creator or an admin may delete a channel")
+        );
+    }
+
+    #[test]
+    fn private_channels_need_an_invite_to_join() {
+        let invite = |id, channel_id| ChannelInvite {
+            id,
+            channel_id,
+            invitee: identity(2),
+            inviter: identity(1),
+            created_at: Timestamp::UNIX_EPOCH,
+        };
+        let private = Channel {
+            invite_only: true,
+            ..channel(7, "secret", 1)
+        };
+        let refused = Err("This channel is invite-only".to_string());
+        assert_eq!(invite_to_consume(&private, &[]), refused);
+        assert_eq!(invite_to_consume(&private, &[invite(1, 8)]), refused);
+        assert_eq!(
+            invite_to_consume(&private, &[invite(1, 8), invite(2, 7)]),
+            Ok(Some(2))
+        );
+
+        let public = channel(8, "lounge", 1);
+        assert_eq!(invite_to_consume(&public, &[]), Ok(None));
+        assert_eq!(invite_to_consume(&public, &[invite(1, 8)]), Ok(None));
+    }
+
+    #[test]
+    fn admin_setup_runs_once_for_the_publisher_or_while_there_are_no_admins() {
+        let config = Config {
+            publisher: identity(1),
+            ..Config::default()
+        };
+        let admins = || vec![identity(2), identity(3), identity(2)];
+        assert_eq!(
+            check_admin_setup(&config, identity(1), true, admins()),
+            Ok(vec![identity(2), identity(3)])
+        );
+        assert!(check_admin_setup(&config, identity(9), false, admins()).is_ok());
+        assert_eq!(
+            check_admin_setup(&config, identity(9), true, admins()),
+            Err("Only the publisher may set up admins".to_string())
+        );
+        assert_eq!(
+            check_admin_setup(&config, identity(1), false, Vec::new()),
+            Err("At least one admin is required".to_string())
+        );
+
+        let complete = Config {
+            setup_complete: true,
+            ..config
+        };
+        assert_eq!(
+            check_admin_setup(&complete, identity(1), false, admins()),
+            Err("setup already complete".to_string())
+        );
+    }
+
+    #[test]
+    fn direct_messages_share_one_conversation_per_pair() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let (me, them) = (identity(2), identity(1));
+        assert_eq!(ordered_pair(me, them), ordered_pair(them, me));
+
+        let (user_a, user_b) = ordered_pair(me, them);
+        let rows = || {
+            [DirectConversation {
+                id: 1,
+                user_a,
+                user_b,
+                conversation_id: 5,
+            }]
+        };
+        assert_eq!(direct_conversation_id(rows(), me, them), Some(5));
+        assert_eq!(direct_conversation_id(rows(), them, me), Some(5));
+        assert_eq!(direct_conversation_id(rows(), me, identity(3)), None);
+
+        let conversation = Conversation {
+            id: 5,
+            creator: me,
+            participants: vec![me, them],
+            created_at: at(0),
+            last_message_at: at(0),
+        };
+        let conversation = touch_conversation(conversation, at(10));
+        let conversation = touch_conversation(conversation, at(20));
+        assert_eq!((conversation.id, conversation.last_message_at), (5, at(20)));
+    }
+
+    #[test]
+    fn seqs_increase_per_channel_independently() {
+        let mut channels = vec![channel(7, "general", 1), channel(8, "random", 1)];
+        let mut taken = Vec::new();
+        for index in [0, 0, 1, 0, 1] {
+            let (seq, advanced) = take_seq(channels.remove(index));
+            taken.push((advanced.id, seq));
+            channels.insert(index, advanced);
+        }
+        assert_eq!(taken, vec![(7, 1), (7, 2), (8, 1), (7, 3), (8, 2)]);
+    }
+
+    #[test]
+    fn idempotency_keys_must_be_short_and_non_empty() {
+        assert_eq!(
+            validate_idempotency_key(""),
+            Err("Idempotency keys must not be empty".to_string())
+        );
+        assert!(validate_idempotency_key(&"k".repeat(MAX_IDEMPOTENCY_KEY_LEN)).is_ok());
+        assert_eq!(
+            validate_idempotency_key(&"k".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1)),
+            Err(format!(
+                "Idempotency keys must be at most {MAX_IDEMPOTENCY_KEY_LEN} bytes"
+            ))
+        );
+    }
+
+    #[test]
+    fn send_keys_block_replays_for_ten_minutes() {
+        let at = Timestamp::from_micros_since_unix_epoch;
+        let recent = [RecentSendKey {
+            id: 1,
+            sender: identity(1),
+            key: "retry-1".to_string(),
+            message_id: 1,
+            created_at: at(0),
+        }];
+        let window = SEND_KEY_WINDOW.as_micros() as i64;
+        assert!(is_replay(&recent, "retry-1", at(window - 1)));
+        assert!(!is_replay(&recent, "retry-2", at(1)));
+        assert!(!is_replay(&recent, "retry-1", at(window)));
+    }
+
+    #[test]
+    fn edits_drop_notifications_for_users_no_longer_mentioned() {
+        let notification = |id, recipient| Notification {
+            id,
+            recipient: identity(recipient),
+            message_id: 1,
+            channel_id: GLOBAL_CHANNEL_ID,
+            sender: identity(9),
+            created: Timestamp::UNIX_EPOCH,
+            delivered: true,
+        };
+        let notifications = [notification(10, 1), notification(11, 2)];
+        assert_eq!(
+            stale_notifications(&notifications, &[identity(2), identity(3)]),
+            vec![10]
+        );
+        assert_eq!(stale_notifications(&notifications, &[]), vec![10, 11]);
+    }
+
+    #[test]
+    fn nick_command_carries_the_new_username() {
+        assert_eq!(
+            parse_slash_command("/nick  alice "),
+            Ok(SlashCommand::Nick("alice".to_string()))
         );
     }
 }